@@ -1,20 +1,39 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use itertools::Itertools;
-use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole},
-    set_api_type, set_base_url, set_key, ApiType,
-};
 use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting, PostgresGucEnum};
 use pgrx::prelude::*;
-use pgrx::spi::quote_qualified_identifier;
+use pgrx::spi::{quote_literal, quote_qualified_identifier};
 use pgrx::JsonB;
+use rand::Rng;
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
 use std::fmt;
-use std::time::Duration;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
 use tokio::time::timeout;
 
+use provider::{CompletionRequest, Message, Role, StopReason};
+
+mod provider;
+mod relevance;
+
 pgrx::pg_module_magic!();
 
 // extension_sql_file!("schema.sql");
+extension_sql!(
+    r#"
+CREATE SCHEMA pg_human;
+CREATE TABLE pg_human.recorded_completions (
+    prompt_hash text PRIMARY KEY,
+    completion text NOT NULL,
+    recorded_at timestamptz NOT NULL DEFAULT now()
+);
+SELECT pg_catalog.pg_extension_config_dump('pg_human.recorded_completions', '');
+"#,
+    name = "recorded_completions",
+);
 //
 #[derive(PostgresGucEnum, Copy, Clone, Eq, PartialEq)]
 pub enum GucApiType {
@@ -22,24 +41,255 @@ pub enum GucApiType {
     Azure,
 }
 
+#[derive(PostgresGucEnum, Copy, Clone, Eq, PartialEq)]
+pub enum GucProvider {
+    OpenAi,
+    Local,
+    Replay,
+    Anthropic,
+    Azure,
+}
+
+#[derive(PostgresGucEnum, Copy, Clone, Eq, PartialEq)]
+pub enum GucLocalProtocol {
+    OpenAi,
+    Ollama,
+}
+
+#[derive(PostgresGucEnum, Copy, Clone, Eq, PartialEq)]
+pub enum GucAzureAuth {
+    Key,
+    Bearer,
+}
+
+static PROVIDER: GucSetting<GucProvider> = GucSetting::new(GucProvider::OpenAi);
+static FALLBACK_PROVIDERS: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static MODEL: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static TEMPERATURE: GucSetting<f64> = GucSetting::new(-1.0);
+static TOP_P: GucSetting<f64> = GucSetting::new(-1.0);
+static MAX_TOKENS: GucSetting<i32> = GucSetting::new(-1);
+static REQUEST_TIMEOUT: GucSetting<i32> = GucSetting::new(60_000);
+static MAX_RETRIES: GucSetting<i32> = GucSetting::new(3);
+static RETRY_BUDGET: GucSetting<i32> = GucSetting::new(120_000);
+static STREAM: GucSetting<bool> = GucSetting::new(false);
+static DESCRIBE_INDEXES: GucSetting<bool> = GucSetting::new(true);
+static DESCRIBE_CHILD_TABLES: GucSetting<bool> = GucSetting::new(false);
+static DESCRIBE_FUNCTIONS: GucSetting<bool> = GucSetting::new(false);
+static SCHEMA_TOKEN_BUDGET: GucSetting<i32> = GucSetting::new(8000);
+static EMBEDDING_MODEL: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static INCLUDE_SCHEMAS: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static EXCLUDE_SCHEMAS: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static INCLUDE_TABLES: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static EXCLUDE_TABLES: GucSetting<Option<&'static str>> = GucSetting::new(None);
 static API_KEY: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static API_KEY_FILE: GucSetting<Option<&'static str>> = GucSetting::new(None);
 static API_TYPE: GucSetting<GucApiType> = GucSetting::new(GucApiType::OpenAi);
 static BASE_URL: GucSetting<Option<&'static str>> =
     GucSetting::new(Some("https://api.openai.com/v1/"));
+static LOCAL_URL: GucSetting<Option<&'static str>> =
+    GucSetting::new(Some("http://localhost:11434/"));
+static LOCAL_PROTOCOL: GucSetting<GucLocalProtocol> = GucSetting::new(GucLocalProtocol::Ollama);
+static LOCAL_API_KEY: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static ANTHROPIC_API_KEY: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static ANTHROPIC_API_KEY_FILE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static ANTHROPIC_BASE_URL: GucSetting<Option<&'static str>> =
+    GucSetting::new(Some("https://api.anthropic.com/v1/"));
+static AZURE_RESOURCE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static AZURE_DEPLOYMENT: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static AZURE_API_VERSION: GucSetting<Option<&'static str>> = GucSetting::new(Some("2024-06-01"));
+static AZURE_AUTH: GucSetting<GucAzureAuth> = GucSetting::new(GucAzureAuth::Key);
+static AZURE_API_KEY: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static AZURE_API_KEY_FILE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static REPLAY_FILE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static RECORD_COMPLETIONS: GucSetting<bool> = GucSetting::new(false);
 #[pg_guard]
 pub extern "C" fn _PG_init() {
+    GucRegistry::define_enum_guc(
+        "pg_human.provider",
+        "The LLM provider that is used by pg_human",
+        "The LLM provider that is used by pg_human",
+        &PROVIDER,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.fallback_providers",
+        "The providers that pg_human falls back to when pg_human.provider fails",
+        "A comma separated list of providers, e.g. 'openai model=gpt-4o-mini, local model=llama3'. When a request to pg_human.provider fails or times out, even after retries, these providers are tried in order. Each provider can be followed by model, url, api_key_file, resource and deployment settings, which it uses instead of the corresponding GUCs. Because the URL determines where API keys are sent, only superusers can change this.",
+        &FALLBACK_PROVIDERS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.model",
+        "The model that pg_human asks the provider to use",
+        "The model that pg_human asks the provider to use. When not set the OpenAI provider uses gpt-3.5-turbo and the Anthropic provider claude-sonnet-4-5, the local provider requires it to be set.",
+        &MODEL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_float_guc(
+        "pg_human.temperature",
+        "The sampling temperature that pg_human asks the model to use",
+        "The sampling temperature that pg_human asks the model to use. Use 0 for reproducible results, or -1 to use the default of the provider.",
+        &TEMPERATURE,
+        -1.0,
+        2.0,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_float_guc(
+        "pg_human.top_p",
+        "The nucleus sampling probability mass that pg_human asks the model to use",
+        "The nucleus sampling probability mass that pg_human asks the model to use. Use -1 to use the default of the provider.",
+        &TOP_P,
+        -1.0,
+        1.0,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_human.max_tokens",
+        "The maximum number of tokens that the model is allowed to generate",
+        "The maximum number of tokens that the model is allowed to generate. Use -1 or 0 to use the default of the provider.",
+        &MAX_TOKENS,
+        -1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_human.request_timeout",
+        "How long pg_human waits for a response from the provider",
+        "How long pg_human waits for a response from the provider. Use 0 to only rely on statement_timeout.",
+        &REQUEST_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        "pg_human.max_retries",
+        "How often pg_human retries a request that failed with a transient error",
+        "How often pg_human retries a request that failed with a transient error, such as a rate limit or a server error",
+        &MAX_RETRIES,
+        0,
+        100,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_human.retry_budget",
+        "The total time after which pg_human stops retrying failed requests",
+        "The total time after which pg_human stops retrying failed requests, counted from the start of the first attempt",
+        &RETRY_BUDGET,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_bool_guc(
+        "pg_human.stream",
+        "Show the query while it is being generated",
+        "Show the query while it is being generated, instead of waiting for the complete answer. This only affects give_me_a_query_to.",
+        &STREAM,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pg_human.describe_indexes",
+        "Include the indexes of the tables in the schema that is sent to the model",
+        "Include the indexes of the tables in the schema that is sent to the model, so that it can take them into account. Indexes that back a primary key, unique or exclusion constraint are already described by that constraint.",
+        &DESCRIBE_INDEXES,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pg_human.describe_child_tables",
+        "Describe partitions and inheritance children as separate tables",
+        "Describe partitions and inheritance children as separate tables. By default only their parent is described, together with the partition key and an example partition.",
+        &DESCRIBE_CHILD_TABLES,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pg_human.describe_functions",
+        "Describe the signatures of user defined functions and procedures",
+        "Describe the signatures of user defined functions and procedures, so that the model can call them instead of reimplementing their logic. Functions of extensions are not described.",
+        &DESCRIBE_FUNCTIONS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_human.schema_token_budget",
+        "The maximum number of tokens that the schema description may use",
+        "The maximum number of tokens that the schema description may use. When the schema is larger, only the tables that are most relevant to the question are described. Use 0 to always describe all tables.",
+        &SCHEMA_TOKEN_BUDGET,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.embedding_model",
+        "The embedding model that is used to find the tables that are relevant to the question",
+        "The embedding model that is used to find the tables that are relevant to the question, when the schema does not fit in pg_human.schema_token_budget. When not set tables are only matched by their names and comments.",
+        &EMBEDDING_MODEL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.include_schemas",
+        "The schemas that pg_human describes to the model",
+        "A comma separated list of schema name patterns, in which * matches any characters. When not set the schemas in the search_path are described.",
+        &INCLUDE_SCHEMAS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.exclude_schemas",
+        "The schemas that pg_human never describes to the model",
+        "A comma separated list of schema name patterns, in which * matches any characters. This takes precedence over pg_human.include_schemas.",
+        &EXCLUDE_SCHEMAS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.include_tables",
+        "The tables that pg_human describes to the model",
+        "A comma separated list of table name patterns, in which * matches any characters. Patterns match the table name, or the schema qualified name when they contain a dot. When not set all tables are described.",
+        &INCLUDE_TABLES,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.exclude_tables",
+        "The tables that pg_human never describes to the model",
+        "A comma separated list of table name patterns, in which * matches any characters. Patterns match the table name, or the schema qualified name when they contain a dot. This takes precedence over pg_human.include_tables.",
+        &EXCLUDE_TABLES,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
     GucRegistry::define_string_guc(
         "pg_human.api_key",
         "The OpenAI API key that is used by pg_human",
         "The OpenAI API key that is used by pg_human",
         &API_KEY,
-        GucContext::Userset,
-        GucFlags::default(),
+        GucContext::Suset,
+        GucFlags::NO_SHOW_ALL | GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.api_key_file",
+        "The file in the data directory that contains the OpenAI API key",
+        "The file in the data directory that contains the OpenAI API key. This is only used when pg_human.api_key is not set.",
+        &API_KEY_FILE,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
     );
     GucRegistry::define_enum_guc(
         "pg_human.api_type",
-        "The OpenAI API key that is used by pg_human",
-        "The OpenAI API key that is used by pg_human",
+        "Deprecated, use pg_human.provider = 'azure' instead",
+        "Deprecated, use pg_human.provider = 'azure' instead. Setting this to azure while pg_human.provider is openai uses the azure provider. Without pg_human.azure_resource that provider then sends requests to pg_human.base_url with pg_human.api_key, like older versions of pg_human did.",
         &API_TYPE,
         GucContext::Userset,
         GucFlags::default(),
@@ -49,18 +299,237 @@ pub extern "C" fn _PG_init() {
         "The OpenAI base URL that is used by pg_human",
         "The OpenAI base URL that is used by pg_human",
         &BASE_URL,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.local_url",
+        "The base URL of the local model server that is used by pg_human",
+        "The base URL of the local model server that is used by pg_human. For servers that speak the OpenAI protocol this should include the /v1/ part.",
+        &LOCAL_URL,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "pg_human.local_protocol",
+        "The chat protocol that the local model server speaks",
+        "The chat protocol that the local model server speaks",
+        &LOCAL_PROTOCOL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.local_api_key",
+        "The optional API key for the local model server",
+        "The optional API key for the local model server",
+        &LOCAL_API_KEY,
+        GucContext::Suset,
+        GucFlags::NO_SHOW_ALL | GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.anthropic_api_key",
+        "The Anthropic API key that is used by pg_human",
+        "The Anthropic API key that is used by pg_human",
+        &ANTHROPIC_API_KEY,
+        GucContext::Suset,
+        GucFlags::NO_SHOW_ALL | GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.anthropic_api_key_file",
+        "The file in the data directory that contains the Anthropic API key",
+        "The file in the data directory that contains the Anthropic API key. This is only used when pg_human.anthropic_api_key is not set.",
+        &ANTHROPIC_API_KEY_FILE,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.anthropic_base_url",
+        "The Anthropic base URL that is used by pg_human",
+        "The Anthropic base URL that is used by pg_human",
+        &ANTHROPIC_BASE_URL,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.azure_resource",
+        "The name of the Azure OpenAI resource that is used by pg_human",
+        "The name of the Azure OpenAI resource that is used by pg_human. Requests are sent to https://{resource}.openai.azure.com/.",
+        &AZURE_RESOURCE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.azure_deployment",
+        "The Azure OpenAI deployment that is used by pg_human",
+        "The Azure OpenAI deployment that is used by pg_human. The deployment determines which model answers, so pg_human.model is not used.",
+        &AZURE_DEPLOYMENT,
         GucContext::Userset,
         GucFlags::default(),
     );
+    GucRegistry::define_string_guc(
+        "pg_human.azure_api_version",
+        "The Azure OpenAI API version that is used by pg_human",
+        "The Azure OpenAI API version that is used by pg_human",
+        &AZURE_API_VERSION,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "pg_human.azure_auth",
+        "How pg_human authenticates to Azure OpenAI",
+        "How pg_human authenticates to Azure OpenAI. With key the credential is sent as an API key, with bearer it is sent as a Microsoft Entra ID token.",
+        &AZURE_AUTH,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.azure_api_key",
+        "The Azure OpenAI API key or token that is used by pg_human",
+        "The Azure OpenAI API key or token that is used by pg_human",
+        &AZURE_API_KEY,
+        GucContext::Suset,
+        GucFlags::NO_SHOW_ALL | GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.azure_api_key_file",
+        "The file in the data directory that contains the Azure OpenAI API key or token",
+        "The file in the data directory that contains the Azure OpenAI API key or token. This is only used when pg_human.azure_api_key is not set. The file is read again for every request, so tokens can be refreshed by replacing it.",
+        &AZURE_API_KEY_FILE,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        "pg_human.replay_file",
+        "The file with recorded completions for the replay provider",
+        "The file with recorded completions for the replay provider, relative to the data directory. When not set the pg_human.recorded_completions table is used.",
+        &REPLAY_FILE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pg_human.record_completions",
+        "Record completions so that the replay provider can serve them later",
+        "Record completions so that the replay provider can serve them later",
+        &RECORD_COMPLETIONS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    unsafe {
+        pg_sys::CacheRegisterRelcacheCallback(
+            Some(relcache_invalidated),
+            pg_sys::Datum::from(0usize),
+        );
+        for cache_id in [
+            pg_sys::SysCacheIdentifier_TYPEOID,
+            pg_sys::SysCacheIdentifier_ENUMOID,
+            pg_sys::SysCacheIdentifier_CONSTROID,
+            pg_sys::SysCacheIdentifier_PROCOID,
+            pg_sys::SysCacheIdentifier_NAMESPACEOID,
+            pg_sys::SysCacheIdentifier_AUTHOID,
+            pg_sys::SysCacheIdentifier_AUTHMEMMEMROLE,
+        ] {
+            pg_sys::CacheRegisterSyscacheCallback(
+                cache_id as i32,
+                Some(syscache_invalidated),
+                pg_sys::Datum::from(0usize),
+            );
+        }
+    }
+}
+
+thread_local! {
+    /// The last description that was built in this backend, together with the
+    /// query and settings that it was built with
+    static DESCRIPTION_CACHE: RefCell<Option<(String, DatabaseDescription)>> = RefCell::new(None);
+    /// Increases whenever the cached description is invalidated
+    static DESCRIPTION_CACHE_GENERATION: Cell<u64> = Cell::new(0);
+}
+
+fn invalidate_description_cache() {
+    DESCRIPTION_CACHE_GENERATION.with(|generation| generation.set(generation.get() + 1));
+    DESCRIPTION_CACHE.with(|cache| {
+        if let Ok(mut cache) = cache.try_borrow_mut() {
+            *cache = None;
+        }
+    });
+}
+
+/// Called when any relation changes, which includes creating and dropping
+/// them, so we don't bother checking which one it was.
+unsafe extern "C" fn relcache_invalidated(_arg: pg_sys::Datum, _relid: pg_sys::Oid) {
+    invalidate_description_cache();
+}
+
+/// Called when types, constraints, schemas or roles change
+unsafe extern "C" fn syscache_invalidated(_arg: pg_sys::Datum, _cache_id: i32, _hash_value: u32) {
+    invalidate_description_cache();
+}
+
+/// Makes all backends forget their cached schema descriptions. Changing a
+/// comment doesn't invalidate any caches by itself, so an event trigger calls
+/// this after COMMENT.
+#[pg_extern]
+fn invalidate_schema_descriptions() {
+    unsafe { pg_sys::CacheInvalidateRelcacheAll() };
 }
 
-#[derive(Debug)]
+// Creating event triggers requires superuser, so without it comment changes
+// only show up after the next other invalidation. The README explains how a
+// superuser can create the trigger afterwards.
+extension_sql!(
+    r#"
+REVOKE ALL ON FUNCTION invalidate_schema_descriptions() FROM PUBLIC;
+CREATE FUNCTION pg_human.comment_changed() RETURNS event_trigger
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = pg_catalog AS $$
+BEGIN
+    PERFORM @extschema@.invalidate_schema_descriptions();
+END
+$$;
+DO $$
+BEGIN
+    IF (SELECT rolsuper FROM pg_catalog.pg_roles WHERE rolname = current_user) THEN
+        CREATE EVENT TRIGGER pg_human_comment_changed ON ddl_command_end
+            WHEN TAG IN ('COMMENT') EXECUTE FUNCTION pg_human.comment_changed();
+    END IF;
+END
+$$;
+"#,
+    name = "comment_changed",
+    requires = ["recorded_completions", invalidate_schema_descriptions],
+);
+
+#[derive(Debug, Clone, Deserialize)]
 struct DatabaseDescription {
+    /// The enums and domains that the columns of the tables use
+    types: Vec<TypeDescription>,
     tables: Vec<TableDescription>,
+    /// Only filled when pg_human.describe_functions is enabled
+    functions: Vec<FunctionDescription>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TypeDescription {
+    /// The name as it is used in the type names of the columns
+    name: String,
+    /// The CREATE TYPE or CREATE DOMAIN statement
+    definition: String,
+    /// The type that a domain is based on, named like `name`
+    base_type: Option<String>,
 }
 
 impl fmt::Display for DatabaseDescription {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for type_description in self.types.iter() {
+            if formatter.alternate() {
+                write!(formatter, "{}\n", type_description.definition)?
+            } else {
+                write!(formatter, "{} ", type_description.definition)?
+            }
+        }
+        if formatter.alternate() && !self.types.is_empty() {
+            write!(formatter, "\n")?
+        }
         for (i, table) in self.tables.iter().enumerate() {
             if formatter.alternate() {
                 if i > 0 {
@@ -71,20 +540,143 @@ impl fmt::Display for DatabaseDescription {
                 write!(formatter, "{table}")?
             }
         }
+        if formatter.alternate() && !self.tables.is_empty() && !self.functions.is_empty() {
+            write!(formatter, "\n\n")?
+        }
+        for (i, function) in self.functions.iter().enumerate() {
+            if formatter.alternate() {
+                if i > 0 {
+                    write!(formatter, "\n")?
+                }
+                write!(formatter, "{function:#}")?
+            } else {
+                write!(formatter, " {function}")?
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize)]
 struct TableDescription {
     schema: String,
     name: String,
+    kind: TableKind,
+    comment: Option<String>,
     columns: Vec<ColumnDescription>,
     constraints: Vec<String>,
+    /// The schema and name of the tables that foreign keys of this table
+    /// reference
+    references: Vec<(String, String)>,
+    /// CREATE INDEX statements for the indexes that are not already described
+    /// by one of the constraints
+    indexes: Vec<String>,
+    /// The PARTITION BY clause of partitioned tables
+    partition_key: Option<String>,
+    /// The number of partitions or inheritance children that the user can
+    /// SELECT from, but that are not described separately
+    hidden_children: i64,
+    /// One of those children, with its partition bound
+    example_child: Option<String>,
+    /// Whether row level security limits the rows that the current user sees
+    row_security: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+enum TableKind {
+    Table,
+    View { definition: String },
+    MaterializedView { definition: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FunctionDescription {
+    schema: String,
+    name: String,
+    /// The argument list, including names and defaults
+    arguments: String,
+    /// The return type, which procedures don't have
+    result: Option<String>,
+    comment: Option<String>,
+}
+
+impl fmt::Display for FunctionDescription {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if formatter.alternate() {
+            if let Some(comment) = &self.comment {
+                write_comment(formatter, comment, "")?;
+            }
+        }
+        let name = quote_qualified_identifier(&self.schema, &self.name);
+        match &self.result {
+            Some(result) => write!(
+                formatter,
+                "CREATE FUNCTION {name}({}) RETURNS {result};",
+                self.arguments
+            ),
+            None => write!(formatter, "CREATE PROCEDURE {name}({});", self.arguments),
+        }
+    }
+}
+
+/// Writes the comment as SQL comment lines, with the given indentation
+fn write_comment(formatter: &mut fmt::Formatter, comment: &str, indent: &str) -> fmt::Result {
+    for line in comment.lines() {
+        write!(formatter, "{indent}-- {line}\n")?;
+    }
+    Ok(())
 }
 
 impl fmt::Display for TableDescription {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if formatter.alternate() {
+            if let Some(comment) = &self.comment {
+                write_comment(formatter, comment, "")?;
+            }
+            if self.row_security {
+                write!(
+                    formatter,
+                    "-- Row level security is enabled, so queries only see the rows that the current user may access.\n"
+                )?;
+            }
+            // The children themselves are not described, so tell the model
+            // that they exist and what they look like
+            let example = match &self.example_child {
+                Some(example) => format!(", like {example}"),
+                None => String::new(),
+            };
+            if self.hidden_children > 0 && self.partition_key.is_some() {
+                write!(
+                    formatter,
+                    "-- Has {} partitions{example}. Query this table instead of its partitions.\n",
+                    self.hidden_children
+                )?;
+            } else if self.hidden_children > 0 {
+                write!(
+                    formatter,
+                    "-- Other tables inherit from this table{example}. Querying this table includes their rows.\n"
+                )?;
+            }
+        }
+        // For views the definition tells the model more than the columns
+        let (keyword, definition) = match &self.kind {
+            TableKind::Table => ("TABLE", None),
+            TableKind::View { definition } => ("VIEW", Some(definition)),
+            TableKind::MaterializedView { definition } => ("MATERIALIZED VIEW", Some(definition)),
+        };
+        if let Some(definition) = definition {
+            let name = quote_qualified_identifier(&self.schema, &self.name);
+            if formatter.alternate() {
+                write!(formatter, "CREATE {keyword} {name} AS\n{definition}")?;
+            } else {
+                write!(
+                    formatter,
+                    "CREATE {keyword} {name} AS {}",
+                    definition.split_whitespace().join(" ")
+                )?;
+            }
+            return self.write_indexes(formatter);
+        }
         write!(
             formatter,
             "CREATE TABLE {}(",
@@ -95,7 +687,11 @@ impl fmt::Display for TableDescription {
                 write!(formatter, ",")?
             }
             if formatter.alternate() {
-                write!(formatter, "\n    {column:#}")?
+                write!(formatter, "\n")?;
+                if let Some(comment) = &column.comment {
+                    write_comment(formatter, comment, "    ")?;
+                }
+                write!(formatter, "    {column:#}")?
             } else {
                 if i > 0 {
                     write!(formatter, " ")?
@@ -114,158 +710,745 @@ impl fmt::Display for TableDescription {
         if formatter.alternate() {
             write!(formatter, "\n")?;
         }
-        write!(formatter, ");")?;
+        write!(formatter, ")")?;
+        if let Some(partition_key) = &self.partition_key {
+            write!(formatter, " PARTITION BY {partition_key}")?;
+        }
+        write!(formatter, ";")?;
+        self.write_indexes(formatter)
+    }
+}
+
+impl TableDescription {
+    fn write_indexes(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for index in self.indexes.iter() {
+            if formatter.alternate() {
+                write!(formatter, "\n{index};")?
+            } else {
+                write!(formatter, " {index};")?
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize)]
 struct ColumnDescription {
     name: String,
     type_name: String,
+    /// The DEFAULT, GENERATED ... AS IDENTITY or GENERATED ALWAYS AS clause
+    default: Option<String>,
+    not_null: bool,
+    comment: Option<String>,
 }
 
 impl fmt::Display for ColumnDescription {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "{} {}", self.name, self.type_name)
+        write!(formatter, "{} {}", self.name, self.type_name)?;
+        if let Some(default) = &self.default {
+            write!(formatter, " {default}")?;
+        }
+        if self.not_null {
+            write!(formatter, " NOT NULL")?;
+        }
+        Ok(())
     }
 }
 
-impl DatabaseDescription {
-    #[must_use]
-    fn new() -> DatabaseDescription {
-        let tables_query = r#"
+/// Generated columns only exist since Postgres 12
+#[cfg(feature = "pg11")]
+const ATTGENERATED: &str = "''";
+#[cfg(not(feature = "pg11"))]
+const ATTGENERATED: &str = "att.attgenerated";
+
+/// Converts a comma separated list of glob patterns into anchored regular
+/// expressions, quoted as SQL literals. Only regex metacharacters are escaped,
+/// because a backslash before a letter is an escape sequence.
+fn pattern_regexes(patterns: &str) -> Vec<String> {
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            let mut regex = "^".to_string();
+            for c in pattern.chars() {
+                match c {
+                    '*' => regex.push_str(".*"),
+                    '?' => regex.push('.'),
+                    c if "\\.^$|()[]{}+".contains(c) => {
+                        regex.push('\\');
+                        regex.push(c);
+                    }
+                    c => regex.push(c),
+                }
+            }
+            regex.push('$');
+            quote_literal(regex)
+        })
+        .collect()
+}
+
+/// Returns the SQL condition that is true if any of the comma separated
+/// patterns matches. `column` returns the SQL expression that a pattern is
+/// matched against.
+fn matches_any(patterns: &str, column: impl Fn(&str) -> &'static str) -> String {
+    let condition = patterns
+        .split(',')
+        .map(|pattern| {
+            let column = column(pattern);
+            pattern_regexes(pattern)
+                .into_iter()
+                .map(|regex| format!("{column} ~ {regex}"))
+                .join(" OR ")
+        })
+        .filter(|condition| !condition.is_empty())
+        .join(" OR ");
+    if condition.is_empty() {
+        "false".to_string()
+    } else {
+        format!("({condition})")
+    }
+}
+
+/// Returns the SQL condition on nsp that selects the schemas whose objects may
+/// be described. Without pg_human.include_schemas only the schemas in the
+/// search_path are described, unless `any_schema` is set. Schemas that the
+/// current user cannot use are never described.
+fn schema_filter(any_schema: bool) -> String {
+    let mut conditions = vec!["pg_catalog.has_schema_privilege(nsp.oid, 'USAGE')".to_string()];
+    match INCLUDE_SCHEMAS.get() {
+        Some(patterns) => conditions.push(matches_any(&patterns, |_| "nsp.nspname")),
+        None if any_schema => {}
+        None => conditions.push("nsp.nspname = ANY(current_schemas(false))".to_string()),
+    }
+    if let Some(patterns) = EXCLUDE_SCHEMAS.get() {
+        conditions.push(format!("NOT {}", matches_any(&patterns, |_| "nsp.nspname")));
+    }
+    conditions.join(" AND ")
+}
+
+/// Returns the SQL condition on nsp and rel that selects the relations that
+/// may be described. The pg_human.include_* and pg_human.exclude_* GUCs
+/// always apply, `tables` restricts this further to the given relations.
+fn relation_filter(tables: Option<&[String]>) -> String {
+    // Table patterns with a dot in them match the qualified name
+    let table_column = |pattern: &str| {
+        if pattern.contains('.') {
+            "nsp.nspname || '.' || rel.relname"
+        } else {
+            "rel.relname"
+        }
+    };
+
+    // Explicitly listed tables don't have to be in the search_path
+    let mut conditions = vec![schema_filter(tables.is_some())];
+    if let Some(patterns) = INCLUDE_TABLES.get() {
+        conditions.push(matches_any(&patterns, table_column));
+    }
+    if let Some(patterns) = EXCLUDE_TABLES.get() {
+        conditions.push(format!("NOT {}", matches_any(&patterns, table_column)));
+    }
+    if let Some(tables) = tables {
+        conditions.push(format!(
+            "rel.oid = ANY(ARRAY[{}]::regclass[])",
+            tables.iter().map(quote_literal).join(", ")
+        ));
+    }
+    conditions.join(" AND ")
+}
+
+/// Returns the SQL condition that is true if the current user may SELECT all
+/// of the given columns of the table. Attribute number 0 stands for an
+/// expression, which is only allowed with SELECT on the whole table.
+fn columns_visible(table: &str, attnums: &str) -> String {
+    format!(
+        "NOT EXISTS (
+            SELECT FROM unnest({attnums}) AS key(attnum)
+            WHERE CASE key.attnum
+                WHEN 0 THEN NOT pg_catalog.has_table_privilege({table}, 'SELECT')
+                ELSE NOT pg_catalog.has_column_privilege({table}, key.attnum, 'SELECT')
+            END
+        )"
+    )
+}
+
+/// Returns the query that builds the whole description of the relations that
+/// may be described, in a single pass over the catalog. The result is a JSON
+/// object in the shape of `DatabaseDescription`.
+fn description_query(tables: Option<&[String]>) -> String {
+    let relation_filter = relation_filter(tables);
+    let describe_indexes = DESCRIBE_INDEXES.get();
+    let describe_child_tables = DESCRIBE_CHILD_TABLES.get();
+    // Relations that are named explicitly are described even if they are a
+    // child of another one
+    let describe_as_child = describe_child_tables || tables.is_some();
+    let describe_functions = DESCRIBE_FUNCTIONS.get();
+    // Functions are not tables, so only the schema GUCs apply to them
+    let function_schema_filter = schema_filter(false);
+    // Constraints, indexes and partition keys would reveal the columns and
+    // tables that they use, so they are only described if the user can see
+    // all of those.
+    let constraint_visible = format!(
+        "{} AND (con.contype <> 'f' OR {})",
+        columns_visible("con.conrelid", "con.conkey"),
+        columns_visible("con.confrelid", "con.confkey")
+    );
+    let index_visible = format!(
+        "{} AND (idx.indpred IS NULL OR pg_catalog.has_table_privilege(idx.indrelid, 'SELECT'))",
+        columns_visible("idx.indrelid", "idx.indkey::int2[]")
+    );
+    let partition_key_visible = columns_visible("part.partrelid", "part.partattrs::int2[]");
+    format!(
+        r#"
+        WITH RECURSIVE visible_relations AS (
+            -- The relations that may be described and of which the current
+            -- user can SELECT at least one column
+            SELECT rel.oid
+            FROM pg_catalog.pg_class rel
+                INNER JOIN pg_catalog.pg_namespace nsp
+                           ON nsp.oid = rel.relnamespace
+            WHERE {relation_filter}
+                AND rel.relkind IN ('r', 'v', 'm', 'f', 'p')
+                AND EXISTS (
+                    SELECT FROM pg_catalog.pg_attribute att
+                    WHERE att.attrelid = rel.oid
+                        AND att.attnum > 0
+                        AND NOT att.attisdropped
+                        AND pg_catalog.has_column_privilege(rel.oid, att.attnum, 'SELECT')
+                )
+        ),
+        described_columns AS (
+            -- This lists the columns of tables, views and materialized views
+            -- that the current user can SELECT from, so that the model only
+            -- learns about what it can use. It also uses the type names that
+            -- you would use in a CREATE TABLE, instead of e.g. ARRAY or
+            -- USER-DEFINED.
             SELECT
-                table_schema::text,
-                table_name::text,
-                column_name::text,
-                data_type::text
-            FROM information_schema.columns
-            WHERE table_schema = ANY(current_schemas(false))
-            ORDER BY table_schema, table_name, ordinal_position;
-            "#;
-        let tables = Spi::connect(|client| {
-            let mut tables: Vec<_> = client
-                .select(tables_query, None, None)
-                .unwrap()
-                .group_by(|row| {
-                    (
-                        row[1].value::<String>().unwrap().unwrap(),
-                        row[2].value::<String>().unwrap().unwrap(),
+                nsp.nspname::text AS table_schema,
+                rel.relname::text AS table_name,
+                att.attname::text AS column_name,
+                pg_catalog.format_type(att.atttypid, att.atttypmod) AS type_name,
+                CASE
+                    WHEN att.attidentity = 'a' THEN 'GENERATED ALWAYS AS IDENTITY'
+                    WHEN att.attidentity = 'd' THEN 'GENERATED BY DEFAULT AS IDENTITY'
+                    WHEN {ATTGENERATED} = 's' THEN
+                        'GENERATED ALWAYS AS (' || pg_catalog.pg_get_expr(def.adbin, def.adrelid) || ') STORED'
+                    ELSE 'DEFAULT ' || pg_catalog.pg_get_expr(def.adbin, def.adrelid)
+                END AS default_clause,
+                att.attnotnull,
+                att.atttypid AS type_oid,
+                att.attnum,
+                rel.oid AS table_oid,
+                rel.relkind::text
+            FROM pg_catalog.pg_attribute att
+                INNER JOIN pg_catalog.pg_class rel
+                           ON rel.oid = att.attrelid
+                INNER JOIN pg_catalog.pg_namespace nsp
+                           ON nsp.oid = rel.relnamespace
+                LEFT JOIN pg_catalog.pg_attrdef def
+                          ON def.adrelid = att.attrelid AND def.adnum = att.attnum
+            WHERE rel.oid IN (SELECT oid FROM visible_relations)
+                -- Partitions and inheritance children are described by
+                -- their parent, if that is described
+                AND (
+                    {describe_as_child}
+                    OR NOT EXISTS (
+                        SELECT FROM pg_catalog.pg_inherits inh
+                        WHERE inh.inhrelid = rel.oid
+                            AND inh.inhparent IN (SELECT oid FROM visible_relations)
                     )
-                })
-                .into_iter()
-                .map(|(key, group)| TableDescription {
-                    schema: key.0,
-                    name: key.1,
-                    columns: group
-                        .map(|row| ColumnDescription {
-                            name: row[3].value::<String>().unwrap().unwrap(),
-                            type_name: row[4].value::<String>().unwrap().unwrap(),
-                        })
-                        .collect(),
-                    constraints: vec![],
-                })
-                .collect();
-
-            // TODO: Use a single query to get all constraints
-            let constraint_query = r#"
-            SELECT pg_get_constraintdef(con.oid)
-               FROM pg_catalog.pg_constraint con
-                    INNER JOIN pg_catalog.pg_class rel
-                               ON rel.oid = con.conrelid
+                )
+                AND att.attnum > 0
+                AND NOT att.attisdropped
+                AND pg_catalog.has_column_privilege(rel.oid, att.attnum, 'SELECT')
+        ),
+        -- The enums and domains that are used by the columns, also when they
+        -- are used as the element type of an array or the base type of a domain.
+        -- The depth is how many steps away from a column the type is used.
+        used_types(oid, depth) AS (
+            SELECT type_oid, 0 FROM described_columns
+            UNION
+            SELECT
+                CASE WHEN typ.typtype = 'd' THEN typ.typbasetype ELSE typ.typelem END,
+                used_types.depth + 1
+            FROM pg_catalog.pg_type typ
+                INNER JOIN used_types ON used_types.oid = typ.oid
+            WHERE typ.typtype = 'd' OR (typ.typelem <> 0 AND typ.typlen = -1)
+        ),
+        described_tables AS (
+            SELECT
+                table_oid,
+                table_schema,
+                table_name,
+                relkind,
+                jsonb_agg(
+                    jsonb_build_object(
+                        'name', column_name,
+                        'type_name', type_name,
+                        'default', default_clause,
+                        'not_null', attnotnull,
+                        'comment', pg_catalog.col_description(table_oid, attnum)
+                    )
+                    ORDER BY attnum
+                ) AS columns
+            FROM described_columns
+            GROUP BY table_oid, table_schema, table_name, relkind
+        )
+        SELECT jsonb_build_object(
+            'types', coalesce((
+                SELECT jsonb_agg(
+                    jsonb_build_object(
+                        'name', typ.oid::regtype::text,
+                        'base_type', CASE WHEN typ.typtype = 'd' THEN typ.typbasetype::regtype::text END,
+                        'definition', CASE typ.typtype
+                            WHEN 'e' THEN format(
+                                'CREATE TYPE %s AS ENUM (%s);',
+                                typ.oid::regtype,
+                                (
+                                    SELECT string_agg(quote_literal(enumlabel), ', ' ORDER BY enumsortorder)
+                                    FROM pg_catalog.pg_enum
+                                    WHERE enumtypid = typ.oid
+                                )
+                            )
+                            ELSE format(
+                                'CREATE DOMAIN %s AS %s%s%s;',
+                                typ.oid::regtype,
+                                pg_catalog.format_type(typ.typbasetype, typ.typtypmod),
+                                CASE WHEN typ.typnotnull THEN ' NOT NULL' ELSE '' END,
+                                (
+                                    SELECT string_agg(' ' || pg_catalog.pg_get_constraintdef(con.oid), '' ORDER BY con.conname)
+                                    FROM pg_catalog.pg_constraint con
+                                    WHERE con.contypid = typ.oid AND con.contype = 'c'
+                                )
+                            )
+                        END
+                    )
+                    -- Types that others are based on come first, so the
+                    -- deepest ones. Enums can't be based on other types.
+                    ORDER BY
+                        (SELECT max(depth) FROM used_types WHERE used_types.oid = typ.oid) DESC,
+                        typ.typtype DESC,
+                        typ.oid::regtype::text
+                )
+                FROM pg_catalog.pg_type typ
+                WHERE typ.oid IN (SELECT oid FROM used_types) AND typ.typtype IN ('e', 'd')
+            ), '[]'),
+            'tables', coalesce((
+                SELECT jsonb_agg(
+                    jsonb_build_object(
+                        'schema', table_schema,
+                        'name', table_name,
+                        'kind', CASE relkind
+                            WHEN 'v' THEN jsonb_build_object(
+                                'View',
+                                jsonb_build_object('definition', pg_catalog.pg_get_viewdef(table_oid, true))
+                            )
+                            WHEN 'm' THEN jsonb_build_object(
+                                'MaterializedView',
+                                jsonb_build_object('definition', pg_catalog.pg_get_viewdef(table_oid, true))
+                            )
+                            ELSE '"Table"'::jsonb
+                        END,
+                        'comment', pg_catalog.obj_description(table_oid, 'pg_class'),
+                        'columns', described_tables.columns,
+                        'constraints', coalesce((
+                            SELECT jsonb_agg(pg_catalog.pg_get_constraintdef(con.oid) ORDER BY con.oid)
+                            FROM pg_catalog.pg_constraint con
+                            WHERE con.conrelid = table_oid AND {constraint_visible}
+                        ), '[]'),
+                        'references', coalesce((
+                            SELECT jsonb_agg(jsonb_build_array(ref_nsp.nspname, ref_rel.relname) ORDER BY con.oid)
+                            FROM pg_catalog.pg_constraint con
+                                INNER JOIN pg_catalog.pg_class ref_rel
+                                           ON ref_rel.oid = con.confrelid
+                                INNER JOIN pg_catalog.pg_namespace ref_nsp
+                                           ON ref_nsp.oid = ref_rel.relnamespace
+                            WHERE con.conrelid = table_oid AND {constraint_visible}
+                        ), '[]'),
+                        -- Indexes that back a constraint are already
+                        -- described by that constraint
+                        'indexes', coalesce((
+                            SELECT jsonb_agg(pg_catalog.pg_get_indexdef(idx.indexrelid) ORDER BY idx_rel.relname)
+                            FROM pg_catalog.pg_index idx
+                                INNER JOIN pg_catalog.pg_class idx_rel
+                                           ON idx_rel.oid = idx.indexrelid
+                            WHERE {describe_indexes}
+                                AND idx.indrelid = table_oid
+                                AND {index_visible}
+                                AND NOT EXISTS (
+                                    SELECT FROM pg_catalog.pg_constraint con
+                                    WHERE con.conindid = idx.indexrelid
+                                        AND con.conrelid = idx.indrelid
+                                        AND con.contype IN ('p', 'u', 'x')
+                                )
+                        ), '[]'),
+                        'partition_key', (
+                            SELECT pg_catalog.pg_get_partkeydef(part.partrelid)
+                            FROM pg_catalog.pg_partitioned_table part
+                            WHERE part.partrelid = table_oid AND {partition_key_visible}
+                        ),
+                        'hidden_children', CASE WHEN {describe_child_tables} THEN 0 ELSE (
+                            SELECT count(*)
+                            FROM pg_catalog.pg_inherits inh
+                            WHERE inh.inhparent = table_oid
+                                AND inh.inhrelid NOT IN (SELECT table_oid FROM described_tables)
+                                AND pg_catalog.has_table_privilege(inh.inhrelid, 'SELECT')
+                        ) END,
+                        'example_child', (
+                            SELECT concat_ws(
+                                ' ',
+                                child.oid::regclass,
+                                pg_catalog.pg_get_expr(child.relpartbound, child.oid)
+                            )
+                            FROM pg_catalog.pg_inherits inh
+                                INNER JOIN pg_catalog.pg_class child
+                                           ON child.oid = inh.inhrelid
+                            WHERE inh.inhparent = table_oid
+                                AND child.oid NOT IN (SELECT table_oid FROM described_tables)
+                                AND pg_catalog.has_table_privilege(child.oid, 'SELECT')
+                            ORDER BY child.relname
+                            LIMIT 1
+                        ),
+                        -- Owners and roles with BYPASSRLS are not limited
+                        -- by row level security, unless it is forced
+                        'row_security', pg_catalog.row_security_active(table_oid)
+                    )
+                    ORDER BY table_schema COLLATE "C", table_name COLLATE "C"
+                )
+                FROM described_tables
+            ), '[]'),
+            'functions', coalesce((
+                SELECT jsonb_agg(
+                    jsonb_build_object(
+                        'schema', nsp.nspname,
+                        'name', pro.proname,
+                        'arguments', pg_catalog.pg_get_function_arguments(pro.oid),
+                        'result', pg_catalog.pg_get_function_result(pro.oid),
+                        'comment', pg_catalog.obj_description(pro.oid, 'pg_proc')
+                    )
+                    ORDER BY nsp.nspname COLLATE "C", pro.proname COLLATE "C", pro.oid
+                )
+                FROM pg_catalog.pg_proc pro
                     INNER JOIN pg_catalog.pg_namespace nsp
-                               ON nsp.oid = connamespace
-               WHERE nsp.nspname = $1 AND rel.relname = $2;
-            "#;
-            for table in tables.iter_mut() {
-                let constraints = client
-                    .select(
-                        constraint_query,
-                        None,
-                        Some(vec![
-                            (
-                                PgBuiltInOids::TEXTOID.oid(),
-                                table.schema.clone().into_datum(),
-                            ),
-                            (
-                                PgBuiltInOids::TEXTOID.oid(),
-                                table.name.clone().into_datum(),
-                            ),
-                        ]),
+                               ON nsp.oid = pro.pronamespace
+                WHERE {describe_functions}
+                    AND {function_schema_filter}
+                    AND pro.prokind IN ('f', 'p')
+                    -- Trigger functions cannot be called from a query
+                    AND pro.prorettype NOT IN (
+                        'pg_catalog.trigger'::pg_catalog.regtype,
+                        'pg_catalog.event_trigger'::pg_catalog.regtype
                     )
-                    .unwrap()
-                    .map(|row| row[1].value::<String>().unwrap().unwrap());
-                table.constraints.extend(constraints);
-            }
-            tables
+                    AND pg_catalog.has_function_privilege(pro.oid, 'EXECUTE')
+                    -- Functions of extensions, like pg_human itself, are
+                    -- not part of the user's schema
+                    AND NOT EXISTS (
+                        SELECT FROM pg_catalog.pg_depend dep
+                        WHERE dep.classid = 'pg_catalog.pg_proc'::pg_catalog.regclass
+                            AND dep.objid = pro.oid
+                            AND dep.deptype = 'e'
+                    )
+            ), '[]')
+        );
+        "#
+    )
+}
+
+impl DatabaseDescription {
+    /// Describes the relations that the pg_human GUCs allow
+    #[cfg(any(test, feature = "pg_test"))]
+    fn new() -> Result<DatabaseDescription> {
+        DatabaseDescription::for_tables(None)
+    }
+
+    /// Describes the relations that the pg_human GUCs allow, or only the
+    /// given `tables`. Descriptions are cached per backend, until DDL
+    /// invalidates them.
+    fn for_tables(tables: Option<&[String]>) -> Result<DatabaseDescription> {
+        let query = description_query(tables);
+        // The query depends on the search_path and the privileges of the
+        // current user, so those are part of the cache key too
+        let key = format!(
+            "{}\n{query}",
+            Spi::get_one::<String>("SELECT format('%s %s', current_user, current_schemas(false))")?
+                .context("could not determine the current user and search_path")?
+        );
+        let cached = DESCRIPTION_CACHE.with(|cache| {
+            cache
+                .borrow()
+                .as_ref()
+                .filter(|(cached_key, _)| *cached_key == key)
+                .map(|(_, description)| description.clone())
         });
-        return DatabaseDescription { tables };
+        if let Some(description) = cached {
+            return Ok(description);
+        }
+
+        let generation = DESCRIPTION_CACHE_GENERATION.with(Cell::get);
+        let description = Spi::get_one::<JsonB>(&query)
+            .context("could not describe the database schema")?
+            .context("the description of the database schema is empty")?;
+        let description: DatabaseDescription = serde_json::from_value(description.0)
+            .context("could not parse the description of the database schema")?;
+        // Don't cache the description if it was invalidated while we were
+        // building it
+        if DESCRIPTION_CACHE_GENERATION.with(Cell::get) == generation {
+            DESCRIPTION_CACHE.with(|cache| *cache.borrow_mut() = Some((key, description.clone())));
+        }
+        Ok(description)
+    }
+
+    fn has_views(&self) -> bool {
+        self.tables
+            .iter()
+            .any(|table| !matches!(table.kind, TableKind::Table))
+    }
+
+    fn has_functions(&self) -> bool {
+        !self.functions.is_empty()
     }
 }
 
-#[must_use]
-fn question_prompt(question: &str) -> Vec<ChatCompletionMessage> {
-    let db_description = DatabaseDescription::new();
-    vec![
-        ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
+fn question_prompt(question: &str, tables: Option<&[String]>) -> Result<Vec<Message>> {
+    let mut db_description = DatabaseDescription::for_tables(tables)?;
+    relevance::fit_to_budget(&mut db_description, question);
+    let mut messages = vec![
+        Message {
+            role: Role::System,
             content: "You are a PostgreSQL expert".to_string(),
-            name: None,
         },
-        ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
+        Message {
+            role: Role::User,
             content: format!("My Postgres database schema looks like this:\n{db_description:#}."),
-            name: None,
         },
-        ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
+        Message {
+            role: Role::User,
             content: format!("Given that schema, could you give me a PostgreSQL query to do the following action: {question}."),
-            name: None,
         },
-        ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
+        Message {
+            role: Role::User,
             content: "Only respond with the code, so no other additional text. Only use the tables and columns provided in the schema.".to_string(),
-            name: None,
         },
-    ]
+    ];
+    if db_description.has_views() {
+        messages.push(Message {
+            role: Role::User,
+            content: "Prefer using the views over the tables they are based on, when a view provides what is needed.".to_string(),
+        });
+    }
+    if db_description.has_functions() {
+        messages.push(Message {
+            role: Role::User,
+            content: "Call the functions and procedures from the schema when they do what is needed, instead of reimplementing their logic.".to_string(),
+        });
+    }
+    Ok(messages)
 }
 
-async fn complete_prompt(prompt: Vec<ChatCompletionMessage>) -> Result<String> {
-    set_key(API_KEY.get().expect("pg_human.api_key is not set"));
-    let api_type = API_TYPE.get();
-    if api_type == GucApiType::OpenAi {
-        set_api_type(ApiType::OpenAi)
-    } else {
-        set_api_type(ApiType::Azure)
+/// Waits for a request to the provider to finish. Sometimes the API seems to
+/// get stuck, so we give up after pg_human.request_timeout. While waiting we
+/// also regularly check for interrupts, so that query cancellation and
+/// statement_timeout abort the request with their usual ERROR.
+async fn wait_for_provider<T>(request: impl Future<Output = Result<T>>) -> Result<T> {
+    let request_timeout = REQUEST_TIMEOUT.get();
+    interruptible(async {
+        if request_timeout == 0 {
+            return request.await;
+        }
+        timeout(Duration::from_millis(request_timeout as u64), request)
+            .await
+            .with_context(|| format!("no response from the provider within {request_timeout}ms"))?
+    })
+    .await
+}
+
+/// Waits for the future to finish, while regularly checking for interrupts.
+async fn interruptible<F: Future>(future: F) -> F::Output {
+    tokio::pin!(future);
+    let mut interrupt_check = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            output = &mut future => return output,
+            _ = interrupt_check.tick() => pgrx::check_for_interrupts!(),
+        }
     }
-    set_base_url(BASE_URL.get().expect("pg_human.base_url is not set"));
-    let request = ChatCompletion::builder("gpt-3.5-turbo", prompt).create();
+}
 
-    // Sometimes the API seems to get stuck, give up after 10 seconds
-    // TODO: Use statement timeout instead
-    let mut response = timeout(Duration::from_secs(60), request).await???;
-    Ok(response.choices.remove(0).message.content)
+/// Keeps calling `attempt` until it succeeds, fails with an error that is not
+/// transient, or we run out of retries or retry budget. Between attempts we
+/// wait as long as the provider asked us to, or otherwise use jittered
+/// exponential backoff.
+async fn with_retries<T, F, Fut>(mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_retries = MAX_RETRIES.get();
+    let retry_budget = Duration::from_millis(RETRY_BUDGET.get() as u64);
+    let start = Instant::now();
+    let mut retries = 0;
+    loop {
+        let err = match attempt().await {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if retries >= max_retries || !provider::is_transient(&err) {
+            return Err(err);
+        }
+        retries += 1;
+        let delay = provider::retry_after(&err).unwrap_or_else(|| {
+            let max_delay = Duration::from_millis(500) * 2u32.pow((retries as u32 - 1).min(6));
+            max_delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+        });
+        if start.elapsed() + delay > retry_budget {
+            return Err(err.context("pg_human.retry_budget was exhausted"));
+        }
+        debug1!(
+            "pg_human retrying in {}ms (retry {retries} of {max_retries}) after error: {err}",
+            delay.as_millis()
+        );
+        interruptible(tokio::time::sleep(delay)).await;
+    }
 }
 
-#[pg_extern]
-#[tokio::main(flavor = "current_thread")]
-async fn give_me_a_query_to(question: &str) -> Result<()> {
-    let prompt = question_prompt(question);
-    notice!(
-        "You can try this query:\n{}",
-        complete_prompt(prompt).await?
+/// Asks the provider to complete the prompt, falling back to the providers in
+/// pg_human.fallback_providers when it fails. If `on_text` is given the answer
+/// is streamed, and `on_text` is called with every piece of it.
+async fn complete_prompt(prompt: Vec<Message>, on_text: Option<&dyn Fn(&str)>) -> Result<String> {
+    let request = CompletionRequest::from_gucs(prompt);
+    let streamed = Cell::new(false);
+    let mut providers = provider::failover_order()?.into_iter().peekable();
+    let (config, completion) = loop {
+        let config = providers
+            .next()
+            .expect("there is always at least one provider");
+        let result = match provider::build(&config) {
+            Ok(provider) => complete_with(&*provider, &request, on_text, &streamed)
+                .await
+                .map(|completion| (provider.endpoint().to_string(), completion)),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok((endpoint, completion)) => {
+                // Fallbacks are already reported to the user, the server log
+                // keeps track of where every answer came from
+                log!("pg_human request was served by the {config} provider at {endpoint}");
+                break (config, completion);
+            }
+            // A broken off stream can't be taken back, so we don't fall back
+            Err(err) if streamed.get() => return Err(err),
+            Err(err) => {
+                let Some(next) = providers.peek() else {
+                    return Err(err);
+                };
+                warning!(
+                    "the {config} provider failed, falling back to the {next} provider: {err:#}"
+                );
+            }
+        }
+    };
+    if completion.stop_reason == StopReason::MaxTokens {
+        warning!("the answer of the model was cut off, because it reached pg_human.max_tokens");
+    }
+    if RECORD_COMPLETIONS.get() && config.kind != GucProvider::Replay {
+        provider::record(&request, &completion)?;
+    }
+    debug1!(
+        "pg_human used {} prompt tokens and {} completion tokens",
+        completion.usage.prompt_tokens,
+        completion.usage.completion_tokens
     );
+    Ok(completion.text)
+}
+
+/// Asks a single provider to complete the request, retrying transient errors.
+async fn complete_with(
+    provider: &dyn provider::Provider,
+    request: &CompletionRequest,
+    on_text: Option<&dyn Fn(&str)>,
+    streamed: &Cell<bool>,
+) -> Result<provider::Completion> {
+    with_retries(|| async move {
+        let Some(on_text) = on_text else {
+            return wait_for_provider(provider.complete(request)).await;
+        };
+        let on_text = |text: &str| {
+            streamed.set(true);
+            on_text(text);
+        };
+        wait_for_provider(provider.complete_streaming(request, &on_text))
+            .await
+            .map_err(|err| {
+                // Retrying would show the start of the answer again, so we
+                // make sure that the error does not count as transient.
+                if streamed.get() {
+                    anyhow!("the answer of the provider broke off: {err:#}")
+                } else {
+                    err
+                }
+            })
+    })
+    .await
+}
+
+/// Runs the future to completion on the Tokio runtime of this backend. The
+/// runtime is created on first use and then kept around, so that repeated
+/// calls don't pay for setting it up again and HTTP connections can be reused.
+fn block_on<F: Future>(future: F) -> F::Output {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to create Tokio runtime")
+        })
+        .block_on(future)
+}
+
+#[pg_extern]
+fn check_provider() -> Result<String> {
+    block_on(wait_for_provider(provider::from_gucs()?.health_check()))
+}
+
+#[pg_extern]
+fn give_me_a_query_to(question: &str, tables: default!(Option<Vec<String>>, "NULL")) -> Result<()> {
+    let prompt = question_prompt(question, tables.as_deref())?;
+    if !STREAM.get() {
+        notice!(
+            "You can try this query:\n{}",
+            block_on(complete_prompt(prompt, None))?
+        );
+        return Ok(());
+    }
+
+    // Send every line as soon as it is complete, one NOTICE per token would
+    // be very noisy.
+    let pending = RefCell::new(String::new());
+    let on_text = |text: &str| {
+        let mut pending = pending.borrow_mut();
+        pending.push_str(text);
+        if let Some(newline) = pending.rfind('\n') {
+            notice!("{}", &pending[..newline]);
+            pending.drain(..=newline);
+        }
+    };
+    let sql = block_on(complete_prompt(prompt, Some(&on_text)))?;
+    let pending = pending.into_inner();
+    if !pending.is_empty() {
+        notice!("{pending}");
+    }
+    notice!("You can try this query:\n{sql}");
     Ok(())
 }
 
 #[pg_extern]
-#[tokio::main(flavor = "current_thread")]
-async fn im_feeling_lucky(
+fn im_feeling_lucky(
     question: &str,
+    tables: default!(Option<Vec<String>>, "NULL"),
 ) -> Result<TableIterator<'static, (name!(i, i32), name!(data, JsonB))>> {
-    let prompt = question_prompt(question);
-    let sql = complete_prompt(prompt).await?;
+    let prompt = question_prompt(question, tables.as_deref())?;
+    let sql = block_on(complete_prompt(prompt, None))?;
     let cleaned_sql = sql.trim_matches('\n').trim_matches('`').trim_end_matches([';', '\n', ' ']);
     notice!("Executing query:\n{sql}");
     // let sql = "SELECT 1 as mynumber";
@@ -291,10 +1474,12 @@ async fn im_feeling_lucky(
 }
 
 #[pg_extern]
-#[tokio::main(flavor = "current_thread")]
-async fn im_feeling_very_lucky(question: &str) -> Result<()> {
-    let prompt = question_prompt(question);
-    let sql = complete_prompt(prompt).await?;
+fn im_feeling_very_lucky(
+    question: &str,
+    tables: default!(Option<Vec<String>>, "NULL"),
+) -> Result<()> {
+    let prompt = question_prompt(question, tables.as_deref())?;
+    let sql = block_on(complete_prompt(prompt, None))?;
     let cleaned_sql = sql.trim_matches('\n').trim_matches('`');
     notice!("Executing:\n{cleaned_sql}");
     Spi::connect(|mut client| {
@@ -308,69 +1493,459 @@ async fn im_feeling_very_lucky(question: &str) -> Result<()> {
 mod tests {
     use super::*;
 
+    /// Creates the schema and makes it the only one in the search_path, so
+    /// that the test only sees the tables that it creates itself
+    fn use_test_schema(schema: &str) {
+        Spi::run(&format!(
+            "CREATE SCHEMA {schema}; SET LOCAL search_path = {schema};"
+        ))
+        .unwrap();
+    }
+
+    /// Returns the description of everything that pg_human would describe
+    fn describe_schema() -> String {
+        format!("{:#}", DatabaseDescription::new().unwrap())
+    }
+
+    /// Returns the description of only the given table
+    fn describe_table(table: &str) -> String {
+        format!(
+            "{:#}",
+            DatabaseDescription::for_tables(Some(&[table.to_string()])).unwrap()
+        )
+    }
+
     #[pg_test]
     fn test_hello_schema_dump() {
-        let expected_schema = r#"CREATE TABLE public.ads(
-    id bigint,
+        use_test_schema("hello");
+        Spi::run(include_str!("schema.sql")).unwrap();
+        let expected_schema = r#"CREATE TABLE hello.ads(
+    id bigint DEFAULT nextval('ads_id_seq'::regclass) NOT NULL,
     company_id bigint,
     campaign_id bigint,
-    name text,
+    name text NOT NULL,
     image_url text,
     target_url text,
-    impressions_count bigint,
-    clicks_count bigint,
-    created_at timestamp without time zone,
-    updated_at timestamp without time zone
+    impressions_count bigint DEFAULT 0,
+    clicks_count bigint DEFAULT 0,
+    created_at timestamp without time zone NOT NULL,
+    updated_at timestamp without time zone NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (campaign_id) REFERENCES campaigns(id),
+    FOREIGN KEY (company_id) REFERENCES companies(id)
 );
 
-CREATE TABLE public.campaigns(
-    id bigint,
+CREATE TABLE hello.campaigns(
+    id bigint DEFAULT nextval('campaigns_id_seq'::regclass) NOT NULL,
     company_id bigint,
-    name text,
-    cost_model text,
-    state text,
+    name text NOT NULL,
+    cost_model text NOT NULL,
+    state text NOT NULL,
     monthly_budget bigint,
-    blacklisted_site_urls ARRAY,
-    created_at timestamp without time zone,
-    updated_at timestamp without time zone
+    blacklisted_site_urls text[],
+    created_at timestamp without time zone NOT NULL,
+    updated_at timestamp without time zone NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (company_id) REFERENCES companies(id)
 );
 
-CREATE TABLE public.clicks(
+CREATE TABLE hello.companies(
+    id bigint DEFAULT nextval('companies_id_seq'::regclass) NOT NULL,
+    name text NOT NULL,
+    image_url text,
+    created_at timestamp without time zone NOT NULL,
+    updated_at timestamp without time zone NOT NULL,
+    PRIMARY KEY (id)
+);"#;
+        assert_eq!(expected_schema, describe_schema());
+    }
+
+    #[pg_test]
+    fn test_enum_and_domain_description() {
+        use_test_schema("enum_test");
+        Spi::run(
+            "CREATE TYPE mood AS ENUM ('sad', 'happy');
+            CREATE DOMAIN positive AS int NOT NULL CHECK (VALUE > 0);
+            CREATE DOMAIN adult_age AS positive CHECK (VALUE >= 18);
+            CREATE TABLE people(mood mood, moods mood[], age adult_age);",
+        )
+        .unwrap();
+        let expected_schema = r#"CREATE TYPE mood AS ENUM ('sad', 'happy');
+CREATE DOMAIN positive AS integer NOT NULL CHECK ((VALUE > 0));
+CREATE DOMAIN adult_age AS positive CHECK ((VALUE >= 18));
+
+CREATE TABLE enum_test.people(
+    mood mood,
+    moods mood[],
+    age adult_age
+);"#;
+        assert_eq!(expected_schema, describe_table("people"));
+    }
+
+    #[pg_test]
+    fn test_view_description() {
+        use_test_schema("view_test");
+        Spi::run(
+            "CREATE TABLE todos(id int, done bool);
+            CREATE VIEW done_todos AS SELECT id FROM todos WHERE done;
+            CREATE MATERIALIZED VIEW todo_count AS SELECT count(*) FROM todos;",
+        )
+        .unwrap();
+        assert!(describe_table("done_todos")
+            .starts_with("CREATE VIEW view_test.done_todos AS\n SELECT "));
+        assert!(describe_table("todo_count")
+            .starts_with("CREATE MATERIALIZED VIEW view_test.todo_count AS\n SELECT "));
+        assert_eq!(
+            "CREATE TABLE view_test.todos(\n    id integer,\n    done boolean\n);",
+            describe_table("todos")
+        );
+        assert!(DatabaseDescription::new().unwrap().has_views());
+    }
+
+    #[pg_test]
+    fn test_comment_description() {
+        use_test_schema("comment_test");
+        Spi::run(
+            "CREATE TABLE campaigns(id bigint, cost_model text);
+            COMMENT ON TABLE campaigns IS 'Advertising campaigns';
+            COMMENT ON COLUMN campaigns.cost_model IS 'cost_model is ''cpc'' or ''cpm''';",
+        )
+        .unwrap();
+        let expected_schema = r#"-- Advertising campaigns
+CREATE TABLE comment_test.campaigns(
     id bigint,
-    company_id bigint,
+    -- cost_model is 'cpc' or 'cpm'
+    cost_model text
+);"#;
+        assert_eq!(expected_schema, describe_table("campaigns"));
+    }
+
+    #[cfg(not(feature = "pg11"))]
+    #[pg_test]
+    fn test_column_default_description() {
+        use_test_schema("default_test");
+        Spi::run(
+            "CREATE TABLE ads(
+                id bigint GENERATED ALWAYS AS IDENTITY,
+                name text NOT NULL,
+                impressions_count bigint NOT NULL DEFAULT 0,
+                clicks_count bigint DEFAULT 0,
+                has_clicks boolean GENERATED ALWAYS AS (clicks_count > 0) STORED
+            );",
+        )
+        .unwrap();
+        let expected_schema = r#"CREATE TABLE default_test.ads(
+    id bigint GENERATED ALWAYS AS IDENTITY NOT NULL,
+    name text NOT NULL,
+    impressions_count bigint DEFAULT 0 NOT NULL,
+    clicks_count bigint DEFAULT 0,
+    has_clicks boolean GENERATED ALWAYS AS ((clicks_count > 0)) STORED
+);"#;
+        assert_eq!(expected_schema, describe_table("ads"));
+    }
+
+    #[pg_test]
+    fn test_index_description() {
+        use_test_schema("index_test");
+        Spi::run(
+            "CREATE TABLE clicks(id bigint PRIMARY KEY, ad_id bigint, clicked_at timestamp);
+            CREATE INDEX clicks_ad_id_idx ON clicks (ad_id, clicked_at);",
+        )
+        .unwrap();
+        let expected_schema = r#"CREATE TABLE index_test.clicks(
+    id bigint NOT NULL,
     ad_id bigint,
     clicked_at timestamp without time zone,
-    site_url text,
-    cost_per_click_usd numeric,
-    user_ip inet,
-    user_data jsonb
+    PRIMARY KEY (id)
 );
+CREATE INDEX clicks_ad_id_idx ON index_test.clicks USING btree (ad_id, clicked_at);"#;
+        assert_eq!(expected_schema, describe_table("clicks"));
 
-CREATE TABLE public.companies(
+        Spi::run("SET LOCAL pg_human.describe_indexes = off").unwrap();
+        assert!(!describe_table("clicks").contains("CREATE INDEX"));
+    }
+
+    #[pg_test]
+    fn test_child_table_description() {
+        use_test_schema("child_test");
+        Spi::run(
+            "CREATE TABLE clicks(id bigint, clicked_at date) PARTITION BY RANGE (clicked_at);
+            CREATE TABLE clicks_2023_01 PARTITION OF clicks
+                FOR VALUES FROM ('2023-01-01') TO ('2023-02-01');
+            CREATE TABLE clicks_2023_02 PARTITION OF clicks
+                FOR VALUES FROM ('2023-02-01') TO ('2023-03-01');
+            CREATE TABLE events(id bigint);
+            CREATE TABLE old_events() INHERITS (events);
+            SET LOCAL pg_human.describe_indexes = off;",
+        )
+        .unwrap();
+        let expected_schema = r#"-- Has 2 partitions, like clicks_2023_01 FOR VALUES FROM ('2023-01-01') TO ('2023-02-01'). Query this table instead of its partitions.
+CREATE TABLE child_test.clicks(
     id bigint,
-    name text,
-    image_url text,
-    created_at timestamp without time zone,
-    updated_at timestamp without time zone
-);
+    clicked_at date
+) PARTITION BY RANGE (clicked_at);
+
+-- Other tables inherit from this table, like old_events. Querying this table includes their rows.
+CREATE TABLE child_test.events(
+    id bigint
+);"#;
+        assert_eq!(expected_schema, describe_schema());
 
-CREATE TABLE public.impressions(
+        // Children are described themselves when they are asked for, or when
+        // their parent isn't described
+        let expected_partition = r#"CREATE TABLE child_test.clicks_2023_01(
     id bigint,
-    company_id bigint,
-    ad_id bigint,
-    seen_at timestamp without time zone,
-    site_url text,
-    cost_per_impression_usd numeric,
-    user_ip inet,
-    user_data jsonb
+    clicked_at date
 );"#;
-        assert_eq!(expected_schema, format!("{:#}", DatabaseDescription::new()));
+        assert_eq!(expected_partition, describe_table("clicks_2023_01"));
+        Spi::run("SET LOCAL pg_human.exclude_tables = 'clicks'").unwrap();
+        let description = describe_schema();
+        assert!(description.contains("CREATE TABLE child_test.clicks_2023_02("));
+        assert!(!description.contains("CREATE TABLE child_test.old_events("));
+        Spi::run(
+            "RESET pg_human.exclude_tables;
+            CREATE ROLE pg_human_partition_reader;
+            GRANT USAGE ON SCHEMA child_test TO pg_human_partition_reader;
+            GRANT SELECT ON clicks_2023_01 TO pg_human_partition_reader;
+            SET LOCAL ROLE pg_human_partition_reader;",
+        )
+        .unwrap();
+        assert_eq!(expected_partition, describe_schema());
+
+        Spi::run("RESET ROLE; SET LOCAL pg_human.describe_child_tables = on").unwrap();
+        let description = describe_schema();
+        assert!(description.contains("CREATE TABLE child_test.clicks_2023_02("));
+        assert!(description.contains("CREATE TABLE child_test.old_events("));
+        assert!(!description.contains("-- Has 2 partitions"));
+    }
+
+    #[pg_test]
+    fn test_function_description() {
+        use_test_schema("function_test");
+        Spi::run(
+            "CREATE TABLE campaigns(id int);
+            CREATE FUNCTION campaign_spend(campaign_id int, period daterange DEFAULT NULL)
+                RETURNS numeric LANGUAGE sql AS 'SELECT 0::numeric';
+            COMMENT ON FUNCTION campaign_spend IS 'Money spent on the campaign';
+            CREATE PROCEDURE archive_campaign(campaign_id int) LANGUAGE sql AS 'SELECT 1';
+            CREATE FUNCTION campaigns_changed() RETURNS trigger LANGUAGE plpgsql
+                AS 'BEGIN RETURN NEW; END';",
+        )
+        .unwrap();
+        assert_eq!(
+            "CREATE TABLE function_test.campaigns(\n    id integer\n);",
+            describe_table("campaigns")
+        );
+
+        Spi::run("SET LOCAL pg_human.describe_functions = on").unwrap();
+        let expected_schema = r#"CREATE TABLE function_test.campaigns(
+    id integer
+);
+
+CREATE PROCEDURE function_test.archive_campaign(campaign_id integer);
+-- Money spent on the campaign
+CREATE FUNCTION function_test.campaign_spend(campaign_id integer, period daterange DEFAULT NULL::daterange) RETURNS numeric;"#;
+        assert_eq!(expected_schema, describe_table("campaigns"));
+    }
+
+    #[pg_test]
+    fn test_privilege_description() {
+        use_test_schema("privilege_test");
+        Spi::run(
+            "CREATE TABLE accounts(id int PRIMARY KEY, email text, password_hash text);
+            CREATE INDEX accounts_email_idx ON accounts (email);
+            CREATE UNIQUE INDEX accounts_password_hash_idx ON accounts (password_hash);
+            CREATE TABLE secrets(id int);
+            CREATE TABLE clicks(id int, day date) PARTITION BY RANGE (day);
+            CREATE TABLE clicks_2023 PARTITION OF clicks
+                FOR VALUES FROM ('2023-01-01') TO ('2024-01-01');
+            ALTER TABLE accounts ENABLE ROW LEVEL SECURITY;
+            CREATE SCHEMA privilege_test_hidden;
+            CREATE TABLE privilege_test_hidden.audits(id int);
+            CREATE FUNCTION privilege_test_hidden.audit_count() RETURNS bigint
+                LANGUAGE sql AS 'SELECT 0::bigint';
+            CREATE ROLE pg_human_analyst;
+            GRANT SELECT ON privilege_test_hidden.audits TO pg_human_analyst;
+            GRANT USAGE ON SCHEMA privilege_test TO pg_human_analyst;
+            GRANT SELECT (id, email) ON accounts TO pg_human_analyst;
+            GRANT SELECT ON clicks TO pg_human_analyst;
+            SET LOCAL ROLE pg_human_analyst;",
+        )
+        .unwrap();
+        // The secrets table and the partition are left out completely
+        let expected_schema = r#"-- Row level security is enabled, so queries only see the rows that the current user may access.
+CREATE TABLE privilege_test.accounts(
+    id integer NOT NULL,
+    email text,
+    PRIMARY KEY (id)
+);
+CREATE INDEX accounts_email_idx ON privilege_test.accounts USING btree (email);
+
+CREATE TABLE privilege_test.clicks(
+    id integer,
+    day date
+) PARTITION BY RANGE (day);"#;
+        assert_eq!(expected_schema, describe_schema());
+
+        // Schemas without USAGE are left out, also when they are included
+        // explicitly
+        Spi::run(
+            "SET LOCAL pg_human.include_schemas = 'privilege_test*';
+            SET LOCAL pg_human.describe_functions = on;",
+        )
+        .unwrap();
+        assert_eq!(expected_schema, describe_schema());
+
+        // The owner isn't limited by row level security, unless it's forced
+        Spi::run(
+            "RESET ROLE;
+            CREATE ROLE pg_human_owner;
+            GRANT USAGE ON SCHEMA privilege_test TO pg_human_owner;
+            ALTER TABLE accounts OWNER TO pg_human_owner;
+            SET LOCAL ROLE pg_human_owner;",
+        )
+        .unwrap();
+        assert!(!describe_table("accounts").contains("Row level security"));
+        Spi::run("ALTER TABLE accounts FORCE ROW LEVEL SECURITY").unwrap();
+        assert!(describe_table("accounts").starts_with("-- Row level security is enabled"));
+    }
+
+    #[pg_test]
+    fn test_relation_filters() {
+        use_test_schema("filter_test");
+        Spi::run(
+            "CREATE SCHEMA filter_test_audit;
+            CREATE TABLE filter_test_audit.log(id int);
+            CREATE TABLE todos(id int);
+            CREATE TABLE todos_internal(id int);
+            CREATE TABLE café(id int);
+            SET LOCAL search_path = filter_test, filter_test_audit;
+            SET LOCAL pg_human.exclude_schemas = '*_aud*';
+            SET LOCAL pg_human.exclude_tables = '*_internal, café';",
+        )
+        .unwrap();
+        assert_eq!(
+            "CREATE TABLE filter_test.todos(\n    id integer\n);",
+            describe_schema()
+        );
+
+        Spi::run("RESET pg_human.exclude_schemas").unwrap();
+        assert_eq!(
+            "CREATE TABLE filter_test_audit.log(\n    id integer\n);",
+            describe_table("filter_test_audit.log")
+        );
+
+        Spi::run("SET LOCAL pg_human.include_tables = 'filter_test_audit.*'").unwrap();
+        assert_eq!(
+            "CREATE TABLE filter_test_audit.log(\n    id integer\n);",
+            describe_schema()
+        );
+    }
+
+    #[pg_test]
+    fn test_schema_token_budget() {
+        use_test_schema("budget_test");
+        Spi::run(
+            "CREATE TABLE campaigns(id int PRIMARY KEY, name text);
+            CREATE TABLE ads(id int, campaign_id int REFERENCES campaigns(id));
+            CREATE TABLE users(id int, email text, password_hash text);
+            SET LOCAL pg_human.schema_token_budget = 70;",
+        )
+        .unwrap();
+        let prompt = question_prompt("count the ads per campaign name", None).unwrap();
+        let description = &prompt[1].content;
+        assert!(description.contains("CREATE TABLE budget_test.ads("));
+        assert!(description.contains("CREATE TABLE budget_test.campaigns("));
+        assert!(!description.contains("CREATE TABLE budget_test.users("));
+
+        // Functions are always described, so fewer tables fit
+        Spi::run(
+            "CREATE FUNCTION campaign_spend(campaign_id int) RETURNS numeric
+                LANGUAGE sql AS 'SELECT 0::numeric';
+            SET LOCAL pg_human.describe_functions = on;",
+        )
+        .unwrap();
+        let prompt = question_prompt("count the ads per campaign name", None).unwrap();
+        let description = &prompt[1].content;
+        assert!(description.contains("CREATE TABLE budget_test.ads("));
+        assert!(description.contains("CREATE FUNCTION budget_test.campaign_spend("));
+        assert!(!description.contains("CREATE TABLE budget_test.campaigns("));
+
+        Spi::run("SET LOCAL pg_human.schema_token_budget = 0").unwrap();
+        let prompt = question_prompt("count the ads per campaign name", None).unwrap();
+        let description = &prompt[1].content;
+        assert!(description.contains("CREATE TABLE budget_test.users("));
+    }
+
+    #[pg_test]
+    fn test_description_cache_invalidation() {
+        use_test_schema("cache_test");
+        Spi::run("CREATE TABLE todos(id int)").unwrap();
+        assert_eq!(
+            "CREATE TABLE cache_test.todos(\n    id integer\n);",
+            describe_schema()
+        );
+
+        Spi::run("ALTER TABLE todos ADD COLUMN done bool").unwrap();
+        assert_eq!(
+            "CREATE TABLE cache_test.todos(\n    id integer,\n    done boolean\n);",
+            describe_schema()
+        );
+
+        Spi::run("COMMENT ON TABLE todos IS 'Things to do'").unwrap();
+        assert!(describe_schema().starts_with("-- Things to do\n"));
     }
 
     #[pg_test]
     fn test_guc() {
         assert_eq!(Some("ABC".to_string()), API_KEY.get())
     }
+
+    /// Makes the replay provider answer the question with the completion
+    fn replay_completion(question: &str, completion: &str) {
+        Spi::run("SET LOCAL pg_human.provider = 'replay'").unwrap();
+        let hash = provider::prompt_hash(&question_prompt(question, None).unwrap());
+        Spi::run_with_args(
+            "INSERT INTO pg_human.recorded_completions (prompt_hash, completion) VALUES ($1, $2)",
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), hash.into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), completion.into_datum()),
+            ]),
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_im_feeling_lucky_replay() {
+        use_test_schema("lucky_test");
+        Spi::run(
+            "CREATE TABLE todos(id int, done bool); INSERT INTO todos VALUES (1, true), (2, false), (3, true);",
+        )
+        .unwrap();
+        replay_completion(
+            "count the finished todos",
+            "```\nSELECT count(*) AS finished FROM todos WHERE done;\n```",
+        );
+        assert_eq!(
+            Some(r#"{"finished": 2}"#.to_string()),
+            Spi::get_one::<String>(
+                "SELECT string_agg(data::text, ',') FROM im_feeling_lucky('count the finished todos')"
+            )
+            .unwrap()
+        );
+    }
+
+    #[pg_test]
+    fn test_im_feeling_very_lucky_replay() {
+        use_test_schema("very_lucky_test");
+        Spi::run("CREATE TABLE todos(id int, done bool);").unwrap();
+        replay_completion("add a todo", "INSERT INTO todos VALUES (1, false);");
+        Spi::run("SELECT im_feeling_very_lucky('add a todo')").unwrap();
+        assert_eq!(
+            Some(1),
+            Spi::get_one::<i64>("SELECT count(*) FROM todos").unwrap()
+        );
+    }
 }
 
 /// This module is required by `cargo pgrx test` invocations.
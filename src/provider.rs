@@ -0,0 +1,515 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::{
+    GucApiType, GucLocalProtocol, GucProvider, API_TYPE, FALLBACK_PROVIDERS, LOCAL_PROTOCOL,
+    MAX_TOKENS, MODEL, PROVIDER, TEMPERATURE, TOP_P,
+};
+
+mod anthropic;
+mod ollama;
+mod openai;
+mod replay;
+
+pub use replay::record;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+/// A chat completion request in a provider independent shape. Sampling
+/// parameters that are `None` are left to the provider's default.
+#[derive(Debug)]
+pub struct CompletionRequest {
+    pub messages: Vec<Message>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+}
+
+impl CompletionRequest {
+    /// Creates a request for the given messages, using the sampling
+    /// parameters from the pg_human GUCs.
+    #[must_use]
+    pub fn from_gucs(messages: Vec<Message>) -> CompletionRequest {
+        // All sampling GUCs use -1 to mean "use the provider default". A
+        // maximum of 0 tokens would never give an answer, so that means the
+        // same for max_tokens.
+        CompletionRequest {
+            messages,
+            temperature: Some(TEMPERATURE.get()).filter(|t| *t >= 0.0),
+            top_p: Some(TOP_P.get()).filter(|p| *p >= 0.0),
+            max_tokens: u32::try_from(MAX_TOKENS.get()).ok().filter(|t| *t > 0),
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+/// Why the model stopped generating its answer
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum StopReason {
+    /// The model considered its answer complete
+    #[default]
+    Finished,
+    /// The answer was cut off, because it reached the maximum number of tokens
+    MaxTokens,
+    /// Any other reason, e.g. a content filter
+    Other,
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    pub text: String,
+    pub usage: Usage,
+    pub stop_reason: StopReason,
+}
+
+/// A backend that can turn a chat prompt into a completion. New backends only
+/// need to implement this trait and get a variant in `GucProvider`.
+#[async_trait(?Send)]
+pub trait Provider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion>;
+
+    /// Like `complete`, but calls `on_text` with every piece of the answer as
+    /// soon as the model generated it. Providers that cannot stream pass the
+    /// whole answer at once.
+    async fn complete_streaming(
+        &self,
+        request: &CompletionRequest,
+        on_text: &dyn Fn(&str),
+    ) -> Result<Completion> {
+        let completion = self.complete(request).await?;
+        on_text(&completion.text);
+        Ok(completion)
+    }
+
+    /// Checks that the backend is reachable and can serve the configured
+    /// model. Returns a short human readable status.
+    async fn health_check(&self) -> Result<String> {
+        Ok("this provider has no health check".to_string())
+    }
+
+    /// Returns an embedding vector for each of the texts, computed by the
+    /// given embedding model.
+    async fn embed(&self, _texts: &[String], _model: &str) -> Result<Vec<Vec<f32>>> {
+        bail!("this provider does not support embeddings")
+    }
+
+    /// Where the requests of this provider go, so that we can tell which
+    /// endpoint served a request.
+    fn endpoint(&self) -> &str;
+}
+
+/// An entry in the failover order: a provider, together with the settings
+/// that it uses instead of the GUCs of that provider. That way we can also
+/// fall back to another endpoint or model of the same kind of provider.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ProviderConfig {
+    pub kind: GucProvider,
+    /// Used instead of pg_human.model
+    model: Option<String>,
+    /// Used instead of pg_human.base_url, pg_human.local_url or
+    /// pg_human.anthropic_base_url
+    url: Option<String>,
+    /// Used instead of the API key GUCs of the provider
+    api_key_file: Option<String>,
+    /// Used instead of pg_human.azure_resource
+    resource: Option<String>,
+    /// Used instead of pg_human.azure_deployment
+    deployment: Option<String>,
+}
+
+impl ProviderConfig {
+    /// The provider, configured only by its GUCs
+    #[must_use]
+    pub fn new(kind: GucProvider) -> ProviderConfig {
+        ProviderConfig {
+            kind,
+            model: None,
+            url: None,
+            api_key_file: None,
+            resource: None,
+            deployment: None,
+        }
+    }
+
+    /// Parses an entry of pg_human.fallback_providers, which is the name of
+    /// the provider followed by settings like model=gpt-4o.
+    fn parse(entry: &str) -> Result<ProviderConfig> {
+        let mut words = entry.split_whitespace();
+        let name = words
+            .next()
+            .context("empty entry in pg_human.fallback_providers")?;
+        let mut config = ProviderConfig::new(parse_name(name)?);
+        for word in words {
+            let (key, value) = word.split_once('=').with_context(|| {
+                format!("expected key=value instead of {word} in pg_human.fallback_providers")
+            })?;
+            let setting = match key {
+                "model" => &mut config.model,
+                "url" => &mut config.url,
+                "api_key_file" => &mut config.api_key_file,
+                "resource" => &mut config.resource,
+                "deployment" => &mut config.deployment,
+                _ => bail!("unknown setting {key} in pg_human.fallback_providers"),
+            };
+            *setting = Some(value.to_string());
+        }
+        Ok(config)
+    }
+
+    /// The model of this entry, or otherwise pg_human.model
+    fn model(&self) -> Option<String> {
+        self.model.clone().or_else(|| MODEL.get())
+    }
+
+    /// The URL of this entry, or otherwise the given URL GUC
+    fn url(&self, guc: Option<String>) -> Option<String> {
+        self.url.clone().or(guc)
+    }
+
+    /// Reads the secret from the api_key_file of this entry, or otherwise
+    /// from the given GUCs like `read_secret` does.
+    fn secret(&self, secret: Option<String>, file: Option<String>) -> Result<Option<String>> {
+        match &self.api_key_file {
+            Some(file) => read_secret(None, Some(file.clone())),
+            None => read_secret(secret, file),
+        }
+    }
+
+    fn resource(&self, guc: Option<String>) -> Option<String> {
+        self.resource.clone().or(guc)
+    }
+
+    fn deployment(&self, guc: Option<String>) -> Option<String> {
+        self.deployment.clone().or(guc)
+    }
+}
+
+/// Shows the entry the way it is written in pg_human.fallback_providers
+impl fmt::Display for ProviderConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", name(self.kind))?;
+        for (key, value) in [
+            ("model", &self.model),
+            ("url", &self.url),
+            ("api_key_file", &self.api_key_file),
+            ("resource", &self.resource),
+            ("deployment", &self.deployment),
+        ] {
+            if let Some(value) = value {
+                write!(formatter, " {key}={value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the provider that is selected by `pg_human.provider`, configured
+/// with the current values of its GUCs.
+pub fn from_gucs() -> Result<Box<dyn Provider>> {
+    build(&ProviderConfig::new(selected()))
+}
+
+/// Returns the provider that is selected by `pg_human.provider`. Older
+/// versions of pg_human selected Azure using `pg_human.api_type`, which still
+/// works but is deprecated.
+fn selected() -> GucProvider {
+    let provider = PROVIDER.get();
+    if provider == GucProvider::OpenAi && API_TYPE.get() == GucApiType::Azure {
+        pgrx::warning!("pg_human.api_type is deprecated, use pg_human.provider = 'azure' instead");
+        return GucProvider::Azure;
+    }
+    provider
+}
+
+/// Returns the providers that should be tried in order: the one selected by
+/// `pg_human.provider`, followed by the entries in
+/// `pg_human.fallback_providers`.
+pub fn failover_order() -> Result<Vec<ProviderConfig>> {
+    let mut providers = vec![ProviderConfig::new(selected())];
+    for entry in FALLBACK_PROVIDERS
+        .get()
+        .iter()
+        .flat_map(|entries| entries.split(','))
+    {
+        if entry.trim().is_empty() {
+            continue;
+        }
+        let provider = ProviderConfig::parse(entry)?;
+        if !providers.contains(&provider) {
+            providers.push(provider);
+        }
+    }
+    Ok(providers)
+}
+
+/// The name of the provider, as it is used in the GUCs
+#[must_use]
+fn name(provider: GucProvider) -> &'static str {
+    match provider {
+        GucProvider::OpenAi => "openai",
+        GucProvider::Local => "local",
+        GucProvider::Replay => "replay",
+        GucProvider::Anthropic => "anthropic",
+        GucProvider::Azure => "azure",
+    }
+}
+
+fn parse_name(value: &str) -> Result<GucProvider> {
+    [
+        GucProvider::OpenAi,
+        GucProvider::Local,
+        GucProvider::Replay,
+        GucProvider::Anthropic,
+        GucProvider::Azure,
+    ]
+    .into_iter()
+    .find(|provider| name(*provider).eq_ignore_ascii_case(value))
+    .with_context(|| format!("unknown provider {value} in pg_human.fallback_providers"))
+}
+
+/// Returns the provider of the entry, configured with the settings of the
+/// entry and the current values of its GUCs.
+pub fn build(config: &ProviderConfig) -> Result<Box<dyn Provider>> {
+    Ok(match config.kind {
+        GucProvider::OpenAi => Box::new(openai::OpenAiProvider::from_gucs(config)?),
+        GucProvider::Local => match LOCAL_PROTOCOL.get() {
+            GucLocalProtocol::OpenAi => Box::new(openai::OpenAiProvider::local_from_gucs(config)?),
+            GucLocalProtocol::Ollama => Box::new(ollama::OllamaProvider::from_gucs(config)?),
+        },
+        GucProvider::Replay => Box::new(replay::ReplayProvider::from_gucs()),
+        GucProvider::Anthropic => Box::new(anthropic::AnthropicProvider::from_gucs(config)?),
+        GucProvider::Azure => Box::new(openai::OpenAiProvider::azure_from_gucs(config)?),
+    })
+}
+
+/// Returns a stable hash of the prompt, which is used as the key for recorded
+/// completions.
+#[must_use]
+pub fn prompt_hash(messages: &[Message]) -> String {
+    let serialized = serde_json::to_string(messages).expect("messages are always serializable");
+    format!("{:x}", Sha256::digest(serialized))
+}
+
+/// An error response from the API of a provider.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// How long the server asked us to wait before retrying
+    pub retry_after: Option<Duration>,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Returns true if the error is likely to go away by itself, i.e. rate limits,
+/// server errors and connection problems. Retrying these is worth it.
+#[must_use]
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    if let Some(err) = err.downcast_ref::<ApiError>() {
+        return err.status == StatusCode::TOO_MANY_REQUESTS || err.status.is_server_error();
+    }
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return err.is_connect() || err.is_timeout();
+    }
+    err.is::<tokio::time::error::Elapsed>()
+}
+
+/// Returns how long the provider asked us to wait before retrying, if it did.
+#[must_use]
+pub fn retry_after(err: &anyhow::Error) -> Option<Duration> {
+    err.downcast_ref::<ApiError>()?.retry_after
+}
+
+/// Returns the HTTP client that all providers share, so that connections to
+/// the same server are kept alive across requests.
+fn http_client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(Client::new).clone()
+}
+
+/// Returns the secret from the GUC that holds it directly, or otherwise from
+/// the file that the second GUC points to. Only files inside the data
+/// directory can be used, so that the GUC cannot be used to read other files.
+fn read_secret(secret: Option<String>, file: Option<String>) -> Result<Option<String>> {
+    if secret.is_some() {
+        return Ok(secret);
+    }
+    let Some(file) = file else {
+        return Ok(None);
+    };
+    let secret = fs::read_to_string(data_directory_path(&file)?)
+        .with_context(|| format!("could not read secret from {file}"))?;
+    Ok(Some(secret.trim().to_string()))
+}
+
+/// Returns the file as a path inside the data directory, or an error if it
+/// points anywhere else. Backends run with the data directory as their
+/// working directory, so relative paths end up there.
+fn data_directory_path(file: &str) -> Result<&Path> {
+    let path = Path::new(file);
+    if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        bail!("{file} is not a relative path inside the data directory");
+    }
+    Ok(path)
+}
+
+/// Turns an HTTP response into the expected JSON body, or into an error that
+/// includes whatever the server told us when the request failed.
+async fn json_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    Ok(successful(response).await?.json().await?)
+}
+
+/// Returns the response if it was successful, and otherwise an error that
+/// includes whatever the server told us.
+async fn successful(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let retry_after = parse_retry_after(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    Err(ApiError {
+        status,
+        retry_after,
+        message: format!("request to {url} failed with {status}: {body}"),
+    }
+    .into())
+}
+
+/// Calls `on_line` for every line of a streamed response body, as soon as the
+/// line has been received completely. Trailing whitespace is removed.
+async fn for_each_line(
+    response: reqwest::Response,
+    mut on_line: impl FnMut(&str) -> Result<()>,
+) -> Result<()> {
+    let mut response = successful(response).await?;
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            on_line(std::str::from_utf8(&line)?.trim_end())?;
+        }
+    }
+    if !buffer.is_empty() {
+        on_line(std::str::from_utf8(&buffer)?.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Parses the Retry-After header, or the more precise retry-after-ms header
+/// that OpenAI sends. Only the delay-seconds form of Retry-After is supported,
+/// for an HTTP date we fall back to our own backoff. So do values that are
+/// negative, infinite or too large for a Duration.
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite() && *value >= 0.0)
+    };
+    if let Some(millis) = header("retry-after-ms") {
+        return Duration::try_from_secs_f64(millis / 1000.0).ok();
+    }
+    Duration::try_from_secs_f64(header(reqwest::header::RETRY_AFTER.as_str())?).ok()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use super::*;
+
+    #[pg_test]
+    fn test_parse_fallback_provider() {
+        let config = ProviderConfig::parse("  Azure resource=backup deployment=gpt-4o ").unwrap();
+        assert!(config.kind == GucProvider::Azure);
+        assert_eq!(
+            "azure resource=backup deployment=gpt-4o",
+            config.to_string()
+        );
+
+        // Only the first = separates the key from the value
+        let config = ProviderConfig::parse("local url=http://localhost:8080/v1?key=value").unwrap();
+        assert_eq!(
+            Some("http://localhost:8080/v1?key=value".to_string()),
+            config.url(None)
+        );
+        assert_eq!(
+            "openai",
+            ProviderConfig::parse("openai").unwrap().to_string()
+        );
+
+        for entry in ["", "   ", "gemini", "openai model", "openai temperature=0"] {
+            assert!(
+                ProviderConfig::parse(entry).is_err(),
+                "{entry:?} was accepted"
+            );
+        }
+    }
+
+    #[pg_test]
+    fn test_parse_retry_after() {
+        let retry_after = |headers: &[(&'static str, &'static str)]| {
+            let mut map = reqwest::header::HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, reqwest::header::HeaderValue::from_static(*value));
+            }
+            parse_retry_after(&map)
+        };
+        assert_eq!(None, retry_after(&[]));
+        assert_eq!(
+            Some(Duration::from_secs(2)),
+            retry_after(&[("retry-after", " 2 ")])
+        );
+        assert_eq!(
+            Some(Duration::from_millis(1500)),
+            retry_after(&[("retry-after", "1.5")])
+        );
+        // OpenAI's more precise header wins
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            retry_after(&[("retry-after", "1"), ("retry-after-ms", "250")])
+        );
+        for value in ["-1", "inf", "NaN", "1e300", "Wed, 21 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(None, retry_after(&[("retry-after", value)]), "{value}");
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    for_each_line, http_client, json_response, Completion, CompletionRequest, Provider,
+    ProviderConfig, Role, StopReason, Usage,
+};
+use crate::{ANTHROPIC_API_KEY, ANTHROPIC_API_KEY_FILE, ANTHROPIC_BASE_URL};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// The Messages API requires max_tokens, this is what we use when
+/// pg_human.max_tokens is not set.
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Anthropic's Messages API
+pub struct AnthropicProvider {
+    client: Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<Message>,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: Role,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: ResponseUsage,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ResponseUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockDelta {
+        delta: ContentDelta,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: ResponseUsage,
+    },
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct StreamMessage {
+    usage: ResponseUsage,
+}
+
+#[derive(Deserialize)]
+struct ContentDelta {
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MessageDelta {
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Models {
+    data: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    id: String,
+}
+
+fn stop_reason(stop_reason: &str) -> StopReason {
+    match stop_reason {
+        "end_turn" | "stop_sequence" => StopReason::Finished,
+        "max_tokens" => StopReason::MaxTokens,
+        _ => StopReason::Other,
+    }
+}
+
+impl MessagesResponse {
+    fn into_completion(self) -> Completion {
+        Completion {
+            text: self
+                .content
+                .into_iter()
+                .filter_map(|block| block.text)
+                .collect(),
+            usage: Usage {
+                prompt_tokens: self.usage.input_tokens,
+                completion_tokens: self.usage.output_tokens,
+            },
+            stop_reason: self
+                .stop_reason
+                .map_or(StopReason::Finished, |reason| stop_reason(&reason)),
+        }
+    }
+}
+
+impl AnthropicProvider {
+    pub fn from_gucs(config: &ProviderConfig) -> Result<AnthropicProvider> {
+        Ok(AnthropicProvider {
+            client: http_client(),
+            base_url: config
+                .url(ANTHROPIC_BASE_URL.get())
+                .ok_or_else(|| anyhow!("pg_human.anthropic_base_url is not set"))?,
+            api_key: config
+                .secret(ANTHROPIC_API_KEY.get(), ANTHROPIC_API_KEY_FILE.get())?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "pg_human.anthropic_api_key or pg_human.anthropic_api_key_file needs to be set"
+                    )
+                })?,
+            model: config
+                .model()
+                .unwrap_or_else(|| "claude-sonnet-4-5".to_string()),
+        })
+    }
+
+    /// The Messages API takes the system prompt as a separate field, and
+    /// requires user and assistant turns to alternate. So we move system
+    /// messages to that field and merge consecutive messages of the same role.
+    fn messages_request<'a>(
+        &'a self,
+        request: &CompletionRequest,
+        stream: bool,
+    ) -> MessagesRequest<'a> {
+        let mut system: Option<String> = None;
+        let mut messages: Vec<Message> = vec![];
+        for message in &request.messages {
+            if message.role == Role::System {
+                match &mut system {
+                    Some(system) => {
+                        system.push_str("\n\n");
+                        system.push_str(&message.content);
+                    }
+                    None => system = Some(message.content.clone()),
+                }
+                continue;
+            }
+            match messages.last_mut() {
+                Some(last) if last.role == message.role => {
+                    last.content.push_str("\n\n");
+                    last.content.push_str(&message.content);
+                }
+                _ => messages.push(Message {
+                    role: message.role,
+                    content: message.content.clone(),
+                }),
+            }
+        }
+        MessagesRequest {
+            model: &self.model,
+            system,
+            messages,
+            max_tokens: request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stream,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let url = format!("{}/{path}", self.base_url.trim_end_matches('/'));
+        self.client
+            .request(method, url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for AnthropicProvider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let response = self
+            .request(Method::POST, "messages")
+            .json(&self.messages_request(request, false))
+            .send()
+            .await?;
+        let response: MessagesResponse = json_response(response).await?;
+        Ok(response.into_completion())
+    }
+
+    async fn complete_streaming(
+        &self,
+        request: &CompletionRequest,
+        on_text: &dyn Fn(&str),
+    ) -> Result<Completion> {
+        let response = self
+            .request(Method::POST, "messages")
+            .json(&self.messages_request(request, true))
+            .send()
+            .await?;
+        let mut completion = Completion {
+            text: String::new(),
+            usage: Usage::default(),
+            stop_reason: StopReason::Finished,
+        };
+        // The response consists of server-sent events, the data lines contain
+        // the type of the event too so we can ignore the event lines.
+        for_each_line(response, |line| {
+            let Some(data) = line.strip_prefix("data:") else {
+                return Ok(());
+            };
+            match serde_json::from_str(data.trim())? {
+                StreamEvent::MessageStart { message } => {
+                    completion.usage.prompt_tokens = message.usage.input_tokens;
+                }
+                StreamEvent::ContentBlockDelta { delta } => {
+                    if let Some(text) = delta.text {
+                        on_text(&text);
+                        completion.text.push_str(&text);
+                    }
+                }
+                StreamEvent::MessageDelta { delta, usage } => {
+                    completion.usage.completion_tokens = usage.output_tokens;
+                    if let Some(reason) = delta.stop_reason {
+                        completion.stop_reason = stop_reason(&reason);
+                    }
+                }
+                StreamEvent::Error { error } => bail!("{}", error.message),
+                StreamEvent::Other => {}
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(completion)
+    }
+
+    async fn health_check(&self) -> Result<String> {
+        let response = self.request(Method::GET, "models").send().await?;
+        let models: Models = json_response(response)
+            .await
+            .map_err(|err| anyhow!("{} is not healthy: {err}", self.base_url))?;
+        let models: Vec<_> = models.data.into_iter().map(|model| model.id).collect();
+        // Models are usually configured by an alias without the date suffix
+        let dated_prefix = format!("{}-", self.model);
+        if !models
+            .iter()
+            .any(|id| *id == self.model || id.starts_with(&dated_prefix))
+        {
+            bail!(
+                "{} does not serve model {}, available models are: {}",
+                self.base_url,
+                self.model,
+                models.join(", ")
+            );
+        }
+        Ok(format!("{} is serving model {}", self.base_url, self.model))
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.base_url
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::provider;
+
+    #[pg_test]
+    fn test_anthropic_messages_request() {
+        let anthropic = AnthropicProvider {
+            client: http_client(),
+            base_url: "https://api.anthropic.com/v1".to_string(),
+            api_key: "ABC".to_string(),
+            model: "claude-sonnet-4-5".to_string(),
+        };
+        let message = |role, content: &str| provider::Message {
+            role,
+            content: content.to_string(),
+        };
+        let request = CompletionRequest {
+            messages: vec![
+                message(Role::System, "You are a PostgreSQL expert"),
+                message(Role::User, "My schema"),
+                message(Role::User, "My question"),
+            ],
+            temperature: Some(0.0),
+            top_p: None,
+            max_tokens: None,
+        };
+        // The system prompt is a separate field and the user turns are merged
+        assert_eq!(
+            json!({
+                "model": "claude-sonnet-4-5",
+                "system": "You are a PostgreSQL expert",
+                "messages": [{"role": "user", "content": "My schema\n\nMy question"}],
+                "max_tokens": DEFAULT_MAX_TOKENS,
+                "temperature": 0.0,
+            }),
+            serde_json::to_value(anthropic.messages_request(&request, false)).unwrap()
+        );
+        let streaming = serde_json::to_value(anthropic.messages_request(&request, true)).unwrap();
+        assert_eq!(json!(true), streaming["stream"]);
+    }
+
+    #[pg_test]
+    fn test_anthropic_stop_reason() {
+        assert_eq!(StopReason::Finished, stop_reason("end_turn"));
+        assert_eq!(StopReason::Finished, stop_reason("stop_sequence"));
+        assert_eq!(StopReason::MaxTokens, stop_reason("max_tokens"));
+        assert_eq!(StopReason::Other, stop_reason("refusal"));
+
+        let response: MessagesResponse = serde_json::from_value(json!({
+            "content": [{"type": "text", "text": "SELECT 1;"}],
+            "stop_reason": "max_tokens",
+            "usage": {"input_tokens": 12, "output_tokens": 34},
+        }))
+        .unwrap();
+        let completion = response.into_completion();
+        assert_eq!("SELECT 1;", completion.text);
+        assert_eq!(StopReason::MaxTokens, completion.stop_reason);
+        assert_eq!(12, completion.usage.prompt_tokens);
+        assert_eq!(34, completion.usage.completion_tokens);
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    for_each_line, http_client, json_response, Completion, CompletionRequest, Message, Provider,
+    ProviderConfig, StopReason, Usage,
+};
+use crate::{LOCAL_API_KEY, LOCAL_URL};
+
+/// A local Ollama server, using its native chat protocol.
+pub struct OllamaProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    stream: bool,
+    options: Options,
+}
+
+#[derive(Serialize)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+}
+
+/// The response, or with streaming each line of the response
+#[derive(Deserialize)]
+struct ChatResponse {
+    message: ResponseMessage,
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+#[derive(Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct Models {
+    models: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    name: String,
+}
+
+fn stop_reason(done_reason: Option<&str>) -> StopReason {
+    match done_reason {
+        None | Some("stop") => StopReason::Finished,
+        Some("length") => StopReason::MaxTokens,
+        Some(_) => StopReason::Other,
+    }
+}
+
+impl OllamaProvider {
+    pub fn from_gucs(config: &ProviderConfig) -> Result<OllamaProvider> {
+        Ok(OllamaProvider {
+            client: http_client(),
+            base_url: config
+                .url(LOCAL_URL.get())
+                .ok_or_else(|| anyhow!("pg_human.local_url is not set"))?,
+            api_key: config.secret(LOCAL_API_KEY.get(), None)?,
+            model: config.model().ok_or_else(|| {
+                anyhow!("pg_human.model needs to be set when using the local provider")
+            })?,
+        })
+    }
+
+    fn chat_request<'a>(&'a self, request: &'a CompletionRequest, stream: bool) -> ChatRequest<'a> {
+        ChatRequest {
+            model: &self.model,
+            messages: &request.messages,
+            stream,
+            options: Options {
+                temperature: request.temperature,
+                top_p: request.top_p,
+                num_predict: request.max_tokens,
+            },
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let url = format!("{}/{path}", self.base_url.trim_end_matches('/'));
+        let builder = self.client.request(method, url);
+        match &self.api_key {
+            Some(api_key) => builder.bearer_auth(api_key),
+            None => builder,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for OllamaProvider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let response = self
+            .request(Method::POST, "api/chat")
+            .json(&self.chat_request(request, false))
+            .send()
+            .await?;
+        let response: ChatResponse = json_response(response).await?;
+        Ok(Completion {
+            text: response.message.content,
+            usage: Usage {
+                prompt_tokens: response.prompt_eval_count,
+                completion_tokens: response.eval_count,
+            },
+            stop_reason: stop_reason(response.done_reason.as_deref()),
+        })
+    }
+
+    async fn complete_streaming(
+        &self,
+        request: &CompletionRequest,
+        on_text: &dyn Fn(&str),
+    ) -> Result<Completion> {
+        let response = self
+            .request(Method::POST, "api/chat")
+            .json(&self.chat_request(request, true))
+            .send()
+            .await?;
+        let mut text = String::new();
+        let mut usage = Usage::default();
+        let mut done_reason = None;
+        // Ollama streams one JSON object per line, the last one has the usage
+        for_each_line(response, |line| {
+            if line.is_empty() {
+                return Ok(());
+            }
+            let chunk: ChatResponse = serde_json::from_str(line)?;
+            on_text(&chunk.message.content);
+            text.push_str(&chunk.message.content);
+            usage.prompt_tokens += chunk.prompt_eval_count;
+            usage.completion_tokens += chunk.eval_count;
+            if chunk.done_reason.is_some() {
+                done_reason = chunk.done_reason;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(Completion {
+            text,
+            usage,
+            stop_reason: stop_reason(done_reason.as_deref()),
+        })
+    }
+
+    async fn health_check(&self) -> Result<String> {
+        let response = self.request(Method::GET, "api/tags").send().await?;
+        let models: Models = json_response(response)
+            .await
+            .map_err(|err| anyhow!("{} is not healthy: {err}", self.base_url))?;
+        let models: Vec<_> = models.models.into_iter().map(|model| model.name).collect();
+        // Ollama adds a :latest tag to models that were pulled without one
+        let latest = format!("{}:latest", self.model);
+        if !models
+            .iter()
+            .any(|name| *name == self.model || *name == latest)
+        {
+            bail!(
+                "{} does not serve model {}, available models are: {}",
+                self.base_url,
+                self.model,
+                models.join(", ")
+            );
+        }
+        Ok(format!("{} is serving model {}", self.base_url, self.model))
+    }
+
+    async fn embed(&self, texts: &[String], model: &str) -> Result<Vec<Vec<f32>>> {
+        let response = self
+            .request(Method::POST, "api/embed")
+            .json(&EmbedRequest {
+                model,
+                input: texts,
+            })
+            .send()
+            .await?;
+        let response: EmbedResponse = json_response(response).await?;
+        Ok(response.embeddings)
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.base_url
+    }
+}
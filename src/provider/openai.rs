@@ -0,0 +1,406 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    for_each_line, http_client, json_response, Completion, CompletionRequest, Message, Provider,
+    ProviderConfig, StopReason, Usage,
+};
+use crate::{
+    GucApiType, GucAzureAuth, API_KEY, API_KEY_FILE, API_TYPE, AZURE_API_KEY, AZURE_API_KEY_FILE,
+    AZURE_API_VERSION, AZURE_AUTH, AZURE_DEPLOYMENT, AZURE_RESOURCE, BASE_URL, LOCAL_API_KEY,
+    LOCAL_URL,
+};
+
+/// Anything that speaks the OpenAI chat protocol: OpenAI itself, Azure OpenAI
+/// Service and local servers like llama.cpp or vLLM. All configuration lives
+/// in the provider itself, so different configurations can be used next to
+/// each other.
+pub struct OpenAiProvider {
+    client: Client,
+    base_url: String,
+    auth: Auth,
+    /// Azure OpenAI Service requires an api-version query parameter on every
+    /// request, other servers don't know about it.
+    api_version: Option<String>,
+    /// Only OpenAI itself is known to accept stream_options, Azure and local
+    /// servers may reject the whole request because of it.
+    stream_usage: bool,
+    model: String,
+}
+
+enum Auth {
+    None,
+    Bearer(String),
+    /// The api-key header that Azure OpenAI Service uses for its keys
+    ApiKey(String),
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// Without include_usage streamed responses don't report the token usage
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+    usage: Option<ResponseUsage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+    usage: Option<ResponseUsage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+#[derive(Deserialize)]
+struct Models {
+    data: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    id: String,
+}
+
+fn stop_reason(finish_reason: &str) -> StopReason {
+    match finish_reason {
+        "stop" => StopReason::Finished,
+        "length" => StopReason::MaxTokens,
+        _ => StopReason::Other,
+    }
+}
+
+impl OpenAiProvider {
+    pub fn from_gucs(config: &ProviderConfig) -> Result<OpenAiProvider> {
+        Ok(OpenAiProvider {
+            client: http_client(),
+            base_url: config
+                .url(BASE_URL.get())
+                .ok_or_else(|| anyhow!("pg_human.base_url is not set"))?,
+            auth: Auth::Bearer(
+                config
+                    .secret(API_KEY.get(), API_KEY_FILE.get())?
+                    .ok_or_else(|| {
+                        anyhow!("pg_human.api_key or pg_human.api_key_file needs to be set")
+                    })?,
+            ),
+            api_version: None,
+            stream_usage: true,
+            model: config
+                .model()
+                .unwrap_or_else(|| "gpt-3.5-turbo".to_string()),
+        })
+    }
+
+    /// A local model server that speaks the OpenAI protocol
+    pub fn local_from_gucs(config: &ProviderConfig) -> Result<OpenAiProvider> {
+        Ok(OpenAiProvider {
+            client: http_client(),
+            base_url: config
+                .url(LOCAL_URL.get())
+                .ok_or_else(|| anyhow!("pg_human.local_url is not set"))?,
+            auth: config
+                .secret(LOCAL_API_KEY.get(), None)?
+                .map_or(Auth::None, Auth::Bearer),
+            api_version: None,
+            stream_usage: false,
+            model: config.model().ok_or_else(|| {
+                anyhow!("pg_human.model needs to be set when using the local provider")
+            })?,
+        })
+    }
+
+    /// Azure OpenAI Service, where the resource and deployment determine the
+    /// URL and the deployment determines the model.
+    pub fn azure_from_gucs(config: &ProviderConfig) -> Result<OpenAiProvider> {
+        let resource = config.resource(AZURE_RESOURCE.get());
+        if resource.is_none() && API_TYPE.get() == GucApiType::Azure {
+            return OpenAiProvider::legacy_azure_from_gucs(config);
+        }
+        let resource =
+            resource.ok_or_else(|| anyhow!("pg_human.azure_resource needs to be set"))?;
+        let deployment = config
+            .deployment(AZURE_DEPLOYMENT.get())
+            .ok_or_else(|| anyhow!("pg_human.azure_deployment needs to be set"))?;
+        // Both end up in the URL, so don't let them point it somewhere else.
+        // Azure doesn't allow dots in these names, and they could be used to
+        // create a .. path segment.
+        for name in [&resource, &deployment] {
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!("{name} is not a valid Azure resource or deployment name");
+            }
+        }
+        let credential = config
+            .secret(AZURE_API_KEY.get(), AZURE_API_KEY_FILE.get())?
+            .ok_or_else(|| {
+                anyhow!("pg_human.azure_api_key or pg_human.azure_api_key_file needs to be set")
+            })?;
+        Ok(OpenAiProvider {
+            client: http_client(),
+            base_url: format!(
+                "https://{resource}.openai.azure.com/openai/deployments/{deployment}/"
+            ),
+            auth: match AZURE_AUTH.get() {
+                GucAzureAuth::Key => Auth::ApiKey(credential),
+                GucAzureAuth::Bearer => Auth::Bearer(credential),
+            },
+            api_version: Some(
+                AZURE_API_VERSION
+                    .get()
+                    .ok_or_else(|| anyhow!("pg_human.azure_api_version needs to be set"))?,
+            ),
+            stream_usage: false,
+            model: deployment,
+        })
+    }
+
+    /// Azure OpenAI Service configured like older versions of pg_human did,
+    /// with pg_human.api_type = 'azure': pg_human.base_url points at the
+    /// deployment and pg_human.api_key contains its key.
+    fn legacy_azure_from_gucs(config: &ProviderConfig) -> Result<OpenAiProvider> {
+        Ok(OpenAiProvider {
+            client: http_client(),
+            base_url: config
+                .url(BASE_URL.get())
+                .ok_or_else(|| anyhow!("pg_human.base_url is not set"))?,
+            auth: Auth::ApiKey(
+                config
+                    .secret(API_KEY.get(), API_KEY_FILE.get())?
+                    .ok_or_else(|| {
+                        anyhow!("pg_human.api_key or pg_human.api_key_file needs to be set")
+                    })?,
+            ),
+            api_version: Some(
+                AZURE_API_VERSION
+                    .get()
+                    .ok_or_else(|| anyhow!("pg_human.azure_api_version needs to be set"))?,
+            ),
+            stream_usage: false,
+            // The deployment in the URL determines the model
+            model: config
+                .model()
+                .unwrap_or_else(|| "gpt-3.5-turbo".to_string()),
+        })
+    }
+
+    fn chat_request<'a>(&'a self, request: &'a CompletionRequest, stream: bool) -> ChatRequest<'a> {
+        ChatRequest {
+            model: &self.model,
+            messages: &request.messages,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            max_tokens: request.max_tokens,
+            stream,
+            stream_options: (stream && self.stream_usage).then_some(StreamOptions {
+                include_usage: true,
+            }),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let url = format!("{}/{path}", self.base_url.trim_end_matches('/'));
+        let mut builder = self.client.request(method, url);
+        if let Some(api_version) = &self.api_version {
+            builder = builder.query(&[("api-version", api_version)]);
+        }
+        match &self.auth {
+            Auth::None => builder,
+            Auth::Bearer(token) => builder.bearer_auth(token),
+            Auth::ApiKey(api_key) => builder.header("api-key", api_key),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for OpenAiProvider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let response = self
+            .request(Method::POST, "chat/completions")
+            .json(&self.chat_request(request, false))
+            .send()
+            .await?;
+        let mut response: ChatResponse = json_response(response).await?;
+        if response.choices.is_empty() {
+            bail!("{} returned no choices", self.base_url);
+        }
+        let usage = response
+            .usage
+            .map(|usage| Usage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+            })
+            .unwrap_or_default();
+        let choice = response.choices.remove(0);
+        Ok(Completion {
+            text: choice.message.content,
+            usage,
+            stop_reason: choice
+                .finish_reason
+                .map_or(StopReason::Finished, |reason| stop_reason(&reason)),
+        })
+    }
+
+    async fn complete_streaming(
+        &self,
+        request: &CompletionRequest,
+        on_text: &dyn Fn(&str),
+    ) -> Result<Completion> {
+        let response = self
+            .request(Method::POST, "chat/completions")
+            .json(&self.chat_request(request, true))
+            .send()
+            .await?;
+        let mut text = String::new();
+        let mut usage = Usage::default();
+        let mut finish_reason = StopReason::Finished;
+        // The response consists of server-sent events, of which we only care
+        // about the data lines
+        for_each_line(response, |line| {
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                return Ok(());
+            };
+            if data == "[DONE]" {
+                return Ok(());
+            }
+            let chunk: StreamChunk = serde_json::from_str(data)?;
+            if let Some(chunk_usage) = chunk.usage {
+                usage = Usage {
+                    prompt_tokens: chunk_usage.prompt_tokens,
+                    completion_tokens: chunk_usage.completion_tokens,
+                };
+            }
+            for choice in chunk.choices {
+                if let Some(reason) = choice.finish_reason {
+                    finish_reason = stop_reason(&reason);
+                }
+                if let Some(content) = choice.delta.content {
+                    on_text(&content);
+                    text.push_str(&content);
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(Completion {
+            text,
+            usage,
+            stop_reason: finish_reason,
+        })
+    }
+
+    async fn health_check(&self) -> Result<String> {
+        if self.api_version.is_some() {
+            // Azure doesn't list models, and the deployment in the URL already
+            // determines the model anyway
+            return Ok("this provider has no health check".to_string());
+        }
+        let response = self.request(Method::GET, "models").send().await?;
+        let models: Models = json_response(response)
+            .await
+            .map_err(|err| anyhow!("{} is not healthy: {err}", self.base_url))?;
+        let models: Vec<_> = models.data.into_iter().map(|model| model.id).collect();
+        if !models.contains(&self.model) {
+            bail!(
+                "{} does not serve model {}, available models are: {}",
+                self.base_url,
+                self.model,
+                models.join(", ")
+            );
+        }
+        Ok(format!("{} is serving model {}", self.base_url, self.model))
+    }
+
+    async fn embed(&self, texts: &[String], model: &str) -> Result<Vec<Vec<f32>>> {
+        if self.api_version.is_some() {
+            // The deployment in the URL is the chat model, not an embedding
+            // model
+            bail!("embeddings are not supported with Azure OpenAI Service");
+        }
+        let response = self
+            .request(Method::POST, "embeddings")
+            .json(&EmbeddingRequest {
+                model,
+                input: texts,
+            })
+            .send()
+            .await?;
+        let mut response: EmbeddingResponse = json_response(response).await?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.base_url
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use pgrx::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+
+use super::{
+    data_directory_path, prompt_hash, Completion, CompletionRequest, Provider, StopReason, Usage,
+};
+use crate::REPLAY_FILE;
+
+/// Serves completions that were recorded earlier, keyed by the hash of the
+/// prompt. The recordings come from the file in pg_human.replay_file, or from
+/// the pg_human.recorded_completions table when that is not set. This allows
+/// testing the whole extension without network access.
+pub struct ReplayProvider {
+    file: Option<String>,
+}
+
+impl ReplayProvider {
+    #[must_use]
+    pub fn from_gucs() -> ReplayProvider {
+        ReplayProvider {
+            file: REPLAY_FILE.get(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for ReplayProvider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let hash = prompt_hash(&request.messages);
+        let text = match &self.file {
+            Some(file) => read_file(file)?.remove(&hash),
+            None => Spi::get_one_with_args::<String>(
+                "SELECT (SELECT completion FROM pg_human.recorded_completions WHERE prompt_hash = $1)",
+                vec![(PgBuiltInOids::TEXTOID.oid(), hash.clone().into_datum())],
+            )?,
+        };
+        let text = text.ok_or_else(|| {
+            anyhow!(
+                "no recorded completion for prompt hash {hash}, you can record one by running the same question with pg_human.record_completions enabled"
+            )
+        })?;
+        Ok(Completion {
+            text,
+            usage: Usage::default(),
+            stop_reason: StopReason::Finished,
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        self.file
+            .as_deref()
+            .unwrap_or("pg_human.recorded_completions")
+    }
+}
+
+/// Stores the completion for the request, so that the replay provider can
+/// serve it later. Recordings in the pg_human.recorded_completions table are
+/// part of the current transaction, so they are lost when the statement that
+/// asked the question fails afterwards, e.g. because the query was invalid.
+/// Files are written immediately and keep their recordings.
+pub fn record(request: &CompletionRequest, completion: &Completion) -> Result<()> {
+    let hash = prompt_hash(&request.messages);
+    match REPLAY_FILE.get() {
+        Some(file) => {
+            let path = data_directory_path(&file)?;
+            let mut recordings = match fs::metadata(path) {
+                Ok(_) => read_file(&file)?,
+                Err(_) => BTreeMap::new(),
+            };
+            recordings.insert(hash, completion.text.clone());
+            fs::write(path, serde_json::to_string_pretty(&recordings)?)?;
+        }
+        None => Spi::run_with_args(
+            r#"
+            INSERT INTO pg_human.recorded_completions (prompt_hash, completion)
+            VALUES ($1, $2)
+            ON CONFLICT (prompt_hash) DO UPDATE
+            SET completion = excluded.completion, recorded_at = now()
+            "#,
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), hash.into_datum()),
+                (
+                    PgBuiltInOids::TEXTOID.oid(),
+                    completion.text.clone().into_datum(),
+                ),
+            ]),
+        )?,
+    }
+    Ok(())
+}
+
+/// Reads a fixture file, which is a JSON object that maps prompt hashes to
+/// completions. Like other files it has to be inside the data directory.
+fn read_file(file: &str) -> Result<BTreeMap<String, String>> {
+    let contents = fs::read_to_string(data_directory_path(file)?)
+        .map_err(|err| anyhow!("could not read replay file {file}: {err}"))?;
+    Ok(serde_json::from_str(&contents)?)
+}
@@ -0,0 +1,220 @@
+use anyhow::{bail, Result};
+use pgrx::prelude::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    block_on, provider, wait_for_provider, DatabaseDescription, TableDescription, EMBEDDING_MODEL,
+    SCHEMA_TOKEN_BUDGET,
+};
+
+thread_local! {
+    /// Embeddings of table descriptions, keyed by model and description, so
+    /// that they are only computed again when a table changes.
+    static EMBEDDINGS: RefCell<HashMap<(String, String), Vec<f32>>> = RefCell::new(HashMap::new());
+}
+
+/// A rough estimate of the number of tokens in the text. Tokenizers differ per
+/// model, but most of them end up around 4 characters per token.
+fn estimate_tokens(text: &str) -> usize {
+    text.len() / 4 + 1
+}
+
+/// Splits the text into lowercase words, without a plural s so that "ads" in
+/// a question matches an ad_id column.
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 1)
+        .map(|word| {
+            let word = word.to_lowercase();
+            match word.strip_suffix('s') {
+                Some(singular) if singular.len() >= 2 && !singular.ends_with('s') => {
+                    singular.to_string()
+                }
+                _ => word,
+            }
+        })
+        .collect()
+}
+
+/// Scores the table by how many words of the question occur in its name,
+/// column names and comments. Table names count the most.
+fn lexical_score(question: &HashSet<String>, table: &TableDescription) -> f64 {
+    let matches = |text: &str| words(text).intersection(question).count() as f64;
+    let mut score = 3.0 * matches(&table.name);
+    if let Some(comment) = &table.comment {
+        score += matches(comment);
+    }
+    for column in table.columns.iter() {
+        score += matches(&column.name);
+        if let Some(comment) = &column.comment {
+            score += 0.5 * matches(comment);
+        }
+    }
+    score
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(a, b)| (a * b) as f64).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| (x * x) as f64).sum::<f64>().sqrt();
+    if norm(a) == 0.0 || norm(b) == 0.0 {
+        return 0.0;
+    }
+    dot / (norm(a) * norm(b))
+}
+
+/// Returns the cosine similarity between the question and each of the tables,
+/// using the embedding model of the provider.
+fn embedding_scores(question: &str, tables: &[TableDescription], model: &str) -> Result<Vec<f64>> {
+    // The alternate format includes the comments, which often say the most
+    // about what a table means
+    let texts: Vec<String> = tables.iter().map(|table| format!("{table:#}")).collect();
+    let key = |text: &str| (model.to_string(), text.to_string());
+    let mut inputs: Vec<String> = EMBEDDINGS.with(|cache| {
+        let cache = cache.borrow();
+        texts
+            .iter()
+            .filter(|text| !cache.contains_key(&key(text)))
+            .cloned()
+            .collect()
+    });
+    inputs.push(question.to_string());
+
+    let provider = provider::from_gucs()?;
+    let mut embeddings = block_on(wait_for_provider(provider.embed(&inputs, model)))?;
+    if embeddings.len() != inputs.len() {
+        bail!(
+            "expected {} embeddings, but the provider returned {}",
+            inputs.len(),
+            embeddings.len()
+        );
+    }
+    let question_embedding = embeddings.pop().unwrap();
+    inputs.pop();
+
+    EMBEDDINGS.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.extend(inputs.iter().map(|text| key(text)).zip(embeddings));
+        // Forget about tables that changed or were dropped
+        let current: HashSet<_> = texts.iter().map(|text| key(text)).collect();
+        cache.retain(|key, _| current.contains(key));
+        Ok(texts
+            .iter()
+            .map(|text| cosine_similarity(&question_embedding, &cache[&key(text)]))
+            .collect())
+    })
+}
+
+/// Leaves out the tables that are least relevant to the question, until the
+/// description fits in pg_human.schema_token_budget. Tables that have a
+/// foreign key relation with a relevant table are considered relevant too,
+/// because they are often needed to join.
+pub fn fit_to_budget(description: &mut DatabaseDescription, question: &str) {
+    let budget = SCHEMA_TOKEN_BUDGET.get();
+    if budget <= 0 || estimate_tokens(&format!("{description:#}")) <= budget as usize {
+        return;
+    }
+    // Types and functions are not left out here, so they always use part of
+    // the budget. For types that's a bit too much, because the ones that only
+    // left out tables use are removed at the end.
+    let reserved: usize = description
+        .types
+        .iter()
+        .map(|type_description| estimate_tokens(&type_description.definition))
+        .chain(
+            description
+                .functions
+                .iter()
+                .map(|function| estimate_tokens(&format!("{function:#}"))),
+        )
+        .sum();
+    let budget = (budget as usize).saturating_sub(reserved);
+    let tables = &description.tables;
+
+    let question_words = words(question);
+    let mut priorities: Vec<f64> = tables
+        .iter()
+        .map(|table| lexical_score(&question_words, table))
+        .collect();
+    if let Some(model) = EMBEDDING_MODEL.get() {
+        match embedding_scores(question, tables, &model) {
+            Ok(similarities) => {
+                for (priority, similarity) in priorities.iter_mut().zip(similarities) {
+                    *priority += 5.0 * similarity;
+                }
+            }
+            Err(err) => warning!("could not rank tables using embeddings: {err:#}"),
+        }
+    }
+
+    let positions: HashMap<(&str, &str), usize> = tables
+        .iter()
+        .enumerate()
+        .map(|(i, table)| ((table.schema.as_str(), table.name.as_str()), i))
+        .collect();
+    let mut neighbours = vec![vec![]; tables.len()];
+    for (i, table) in tables.iter().enumerate() {
+        for (schema, name) in table.references.iter() {
+            if let Some(&j) = positions.get(&(schema.as_str(), name.as_str())) {
+                if i != j {
+                    neighbours[i].push(j);
+                    neighbours[j].push(i);
+                }
+            }
+        }
+    }
+
+    let sizes: Vec<usize> = tables
+        .iter()
+        .map(|table| estimate_tokens(&format!("{table:#}")))
+        .collect();
+    let mut selected = vec![false; tables.len()];
+    let mut considered = vec![false; tables.len()];
+    let mut tokens = 0;
+    // Ties are broken by the original order of the tables
+    while let Some(next) = (0..tables.len())
+        .filter(|&i| !considered[i])
+        .max_by(|&a, &b| priorities[a].total_cmp(&priorities[b]).then(b.cmp(&a)))
+    {
+        considered[next] = true;
+        if tokens + sizes[next] > budget {
+            continue;
+        }
+        tokens += sizes[next];
+        selected[next] = true;
+        for &neighbour in neighbours[next].iter() {
+            priorities[neighbour] = priorities[neighbour].max(priorities[next] / 2.0);
+        }
+    }
+
+    debug1!(
+        "pg_human describes {} of {} tables to stay within pg_human.schema_token_budget",
+        selected.iter().filter(|selected| **selected).count(),
+        tables.len()
+    );
+    let mut selected = selected.into_iter();
+    description
+        .tables
+        .retain(|_| selected.next().unwrap_or(false));
+
+    // Only keep the types that are used by the remaining columns, or by the
+    // remaining domains. Types come after the types that they are based on,
+    // so going backwards we see a domain before its base type.
+    let mut used_types: HashSet<String> = description
+        .tables
+        .iter()
+        .flat_map(|table| table.columns.iter())
+        .map(|column| column.type_name.trim_end_matches("[]").to_string())
+        .collect();
+    for type_description in description.types.iter().rev() {
+        if !used_types.contains(&type_description.name) {
+            continue;
+        }
+        if let Some(base_type) = &type_description.base_type {
+            used_types.insert(base_type.trim_end_matches("[]").to_string());
+        }
+    }
+    description
+        .types
+        .retain(|type_description| used_types.contains(&type_description.name));
+}